@@ -79,6 +79,35 @@ Create the name of the cluster role to use
 {{- end }}
 {{- end }}
 
+{{/*
+Namespaces cert-sync is restricted to, comma separated. Empty when watching
+the whole cluster
+*/}}
+{{- define "cert-sync.allowedNamespaces" -}}
+{{- with .Values.config.kubernetes }}
+{{- with .namespaces }}
+{{- join "," (.allow | default list) }}
+{{- end }}
+{{- end }}
+{{- end }}
+
+{{/*
+Kind of Kubernetes objects holding the certificates, certificate as soon as a
+source watches cert-manager Certificates, secret otherwise
+*/}}
+{{- define "cert-sync.watchedResource" -}}
+{{- $resource := "secret" }}
+{{- with .Values.config.kubernetes }}
+{{- $resource = .resource | default "secret" }}
+{{- end }}
+{{- range .Values.config.sources }}
+{{- if eq (.resource | default "") "certificate" }}
+{{- $resource = "certificate" }}
+{{- end }}
+{{- end }}
+{{- $resource }}
+{{- end }}
+
 {{/*
 Create the name of the cluster role binding to use
 */}}
@@ -93,6 +122,18 @@ Create the name of the cert-sync config map to use
 {{- printf "%s-config" (include "cert-sync.fullname" .) }}
 {{- end }}
 
+{{/*
+Define the name of the Secret holding the AWS credentials, empty when the
+credentials do not come from a Secret
+*/}}
+{{- define "cert-sync.awsCredentialsSecretName" -}}
+{{- if .Values.awsCredentialsSecret }}
+{{- .Values.awsCredentialsSecret }}
+{{- else if (.Values.config.aws | default dict).credentials }}
+{{- printf "%s-aws-credentials" (include "cert-sync.fullname" .) }}
+{{- end }}
+{{- end }}
+
 {{/*
 Define the HTTP Proxy
 */}}
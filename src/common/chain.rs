@@ -0,0 +1,238 @@
+use super::name_to_string;
+
+use openssl::{
+    error::ErrorStack,
+    stack::Stack,
+    x509::{
+        store::X509StoreBuilder, verify::X509VerifyFlags, X509Ref, X509StoreContext,
+        X509VerifyResult, X509,
+    },
+};
+
+/// Errors raised when a certificate chain cannot be built
+#[derive(Debug, thiserror::Error)]
+pub enum ChainError {
+    #[error("no certificate found")]
+    Empty,
+    #[error("unable to find the leaf certificate, every certificate issues another one")]
+    NoLeaf,
+    #[error("certificate {subject} is not part of the chain of {leaf}")]
+    Unrelated { subject: String, leaf: String },
+    #[error("chain of {leaf} does not verify : {reason}")]
+    Invalid { leaf: String, reason: String },
+    #[error(transparent)]
+    OpenSsl(#[from] ErrorStack),
+}
+
+/// Builds the chain of the leaf certificate out of `certs`, as found in a
+/// certificate file, and `extra` ones, e.g. the CA certificates
+///
+/// The leaf is the first of `certs` issuing no other certificate. The
+/// returned chain is ordered from the leaf issuer to the root, without
+/// duplicates nor self-signed root, which ACM rejects. It is verified with
+/// the topmost certificate trusted, validity windows are not checked.
+pub fn build_chain(certs: Vec<X509>, extra: Vec<X509>) -> Result<(X509, Vec<X509>), ChainError> {
+    if certs.is_empty() {
+        return Err(ChainError::Empty);
+    }
+    // Each certificate is paired with whether it comes from the certificate
+    // file, a certificate found in both counting as such
+    let mut pool: Vec<(X509, bool)> = vec![];
+    for (cert, from_file) in certs
+        .into_iter()
+        .map(|cert| (cert, true))
+        .chain(extra.into_iter().map(|cert| (cert, false)))
+    {
+        if !contains(&pool, &cert)? {
+            pool.push((cert, from_file));
+        }
+    }
+    let leaf_index = (0..pool.len())
+        .take_while(|index| pool[*index].1)
+        .find(|index| {
+            !pool
+                .iter()
+                .enumerate()
+                .any(|(other, (cert, _))| other != *index && issues(&pool[*index].0, cert))
+        })
+        .ok_or(ChainError::NoLeaf)?;
+    let (leaf, _) = pool.remove(leaf_index);
+    let mut chain: Vec<X509> = vec![];
+    let mut root: Option<X509> = None;
+    let mut current: X509 = leaf.clone();
+    while !is_self_signed(&current) {
+        let issuer_index = match pool.iter().position(|(cert, _)| issues(cert, &current)) {
+            Some(index) => index,
+            None => break,
+        };
+        let (issuer, _) = pool.remove(issuer_index);
+        if is_self_signed(&issuer) {
+            root = Some(issuer);
+            break;
+        }
+        chain.push(issuer.clone());
+        current = issuer;
+    }
+    // Unused CA certificates are expected, e.g. with a `ca.crt` holding
+    // several roots or spare intermediates, but not unused certificates from
+    // the certificate file
+    let unused = pool
+        .iter()
+        .find(|(cert, from_file)| *from_file && !is_self_signed(cert));
+    if let Some((cert, _)) = unused {
+        return Err(ChainError::Unrelated {
+            subject: subject(cert),
+            leaf: subject(&leaf),
+        });
+    }
+    verify(&leaf, &chain, root)?;
+    Ok((leaf, chain))
+}
+
+/// Verifies the signatures from the leaf to the root, or to the topmost
+/// certificate of the chain when the root is missing
+fn verify(leaf: &X509, chain: &[X509], root: Option<X509>) -> Result<(), ChainError> {
+    let top = match root.or_else(|| chain.last().cloned()) {
+        Some(top) => top,
+        None => return Ok(()),
+    };
+    let mut store = X509StoreBuilder::new()?;
+    store.add_cert(top)?;
+    store.set_flags(X509VerifyFlags::PARTIAL_CHAIN | X509VerifyFlags::NO_CHECK_TIME)?;
+    let store = store.build();
+    let mut untrusted = Stack::new()?;
+    for cert in chain {
+        untrusted.push(cert.clone())?;
+    }
+    let mut context = X509StoreContext::new()?;
+    let error = context.init(&store, leaf, &untrusted, |context| {
+        Ok(match context.verify_cert()? {
+            true => None,
+            false => Some(context.error()),
+        })
+    })?;
+    match error {
+        Some(error) => Err(ChainError::Invalid {
+            leaf: subject(leaf),
+            reason: String::from(error.error_string()),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn contains(pool: &[(X509, bool)], cert: &X509) -> Result<bool, ErrorStack> {
+    let der = cert.to_der()?;
+    for (other, _) in pool {
+        if other.to_der()? == der {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Tells whether `issuer` has issued `subject`, from their names and key
+/// identifiers only
+fn issues(issuer: &X509Ref, subject: &X509Ref) -> bool {
+    issuer.issued(subject) == X509VerifyResult::OK
+}
+
+fn is_self_signed(cert: &X509Ref) -> bool {
+    issues(cert, cert)
+        && cert
+            .public_key()
+            .and_then(|key| cert.verify(&key))
+            .unwrap_or(false)
+}
+
+/// Formats the subject of a certificate for error messages
+fn subject(cert: &X509Ref) -> String {
+    name_to_string(cert.subject_name()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_chain, ChainError};
+    use crate::common::testing::{certificate, generate_key};
+    use openssl::x509::X509;
+
+    fn common_name(cert: &X509) -> String {
+        let entry = cert.subject_name().entries().next().unwrap();
+        entry.data().to_string().unwrap()
+    }
+
+    #[test]
+    fn order_chain() -> anyhow::Result<()> {
+        let root_key = generate_key();
+        let root = certificate("Root", &root_key, None);
+        let intermediate_key = generate_key();
+        let intermediate = certificate("Intermediate", &intermediate_key, Some((&root, &root_key)));
+        let leaf = certificate(
+            "example.org",
+            &generate_key(),
+            Some((&intermediate, &intermediate_key)),
+        );
+        let (built_leaf, chain) = build_chain(
+            vec![
+                intermediate.clone(),
+                root.clone(),
+                leaf.clone(),
+                intermediate.clone(),
+            ],
+            vec![root.clone()],
+        )?;
+        assert_eq!("example.org", common_name(&built_leaf));
+        let names: Vec<String> = chain.iter().map(common_name).collect();
+        assert_eq!(vec!["Intermediate"], names);
+
+        // The root may only come from the CA certificates
+        let (_, chain) = build_chain(vec![leaf.clone()], vec![intermediate, root])?;
+        assert_eq!(1, chain.len());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_bad_chains() {
+        let root_key = generate_key();
+        let root = certificate("Root", &root_key, None);
+        let intermediate_key = generate_key();
+        let intermediate = certificate("Intermediate", &intermediate_key, Some((&root, &root_key)));
+        let leaf = certificate(
+            "example.org",
+            &generate_key(),
+            Some((&intermediate, &intermediate_key)),
+        );
+        let unrelated_key = generate_key();
+        let unrelated = certificate("Unrelated", &unrelated_key, Some((&root, &root_key)));
+        assert!(matches!(
+            build_chain(vec![], vec![]),
+            Err(ChainError::Empty)
+        ));
+        let error = build_chain(vec![leaf.clone(), unrelated], vec![]).unwrap_err();
+        assert_eq!(
+            "certificate CN=Unrelated is not part of the chain of CN=example.org",
+            error.to_string()
+        );
+        // Same name as the real intermediate, but a different key
+        let forged = certificate("Intermediate", &generate_key(), Some((&root, &root_key)));
+        let error = build_chain(vec![leaf, forged], vec![root]).unwrap_err();
+        assert!(matches!(error, ChainError::Invalid { .. }));
+    }
+
+    #[test]
+    fn ignore_spare_ca_intermediates() -> anyhow::Result<()> {
+        let root_key = generate_key();
+        let root = certificate("Root", &root_key, None);
+        let intermediate_key = generate_key();
+        let intermediate = certificate("Intermediate", &intermediate_key, Some((&root, &root_key)));
+        let leaf = certificate(
+            "example.org",
+            &generate_key(),
+            Some((&intermediate, &intermediate_key)),
+        );
+        let spare = certificate("Spare", &generate_key(), Some((&root, &root_key)));
+        let (_, chain) = build_chain(vec![leaf], vec![spare, intermediate, root])?;
+        let names: Vec<String> = chain.iter().map(common_name).collect();
+        assert_eq!(vec!["Intermediate"], names);
+        Ok(())
+    }
+}
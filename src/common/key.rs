@@ -0,0 +1,156 @@
+use super::pem::{self, PemError};
+
+use openssl::{
+    error::ErrorStack,
+    pkey::{Id, PKey, Private},
+};
+
+/// Errors raised when a private key cannot be converted for ACM
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error("private key is encrypted and no passphrase is configured")]
+    MissingPassphrase,
+    #[error("unable to decrypt private key, the passphrase may be wrong : {0}")]
+    Undecryptable(ErrorStack),
+    #[error("unable to parse private key : {0}")]
+    Unparsable(ErrorStack),
+    #[error("private key is not valid PEM : {0}")]
+    InvalidPem(#[from] PemError),
+    #[error("private key algorithm {0:?} is not supported")]
+    Unsupported(Id),
+    #[error(transparent)]
+    OpenSsl(#[from] ErrorStack),
+}
+
+/// Converts a private key to the PEM encoding accepted by ACM, i.e. PKCS#1
+/// for RSA keys and SEC1 for EC keys
+///
+/// The key may be PEM or DER encoded, in PKCS#1, PKCS#8 or SEC1 format, and
+/// encrypted when a `passphrase` is given.
+pub fn normalize(key: &[u8], passphrase: Option<&[u8]>) -> Result<String, KeyError> {
+    let pkey = match std::str::from_utf8(key) {
+        Ok(text) if text.contains("-----BEGIN ") => from_pem(text, passphrase)?,
+        _ => from_der(key, passphrase)?,
+    };
+    let pem = match pkey.id() {
+        Id::RSA => pkey.rsa()?.private_key_to_pem()?,
+        Id::EC => pkey.ec_key()?.private_key_to_pem()?,
+        id => return Err(KeyError::Unsupported(id)),
+    };
+    Ok(String::from_utf8_lossy(&pem).into_owned())
+}
+
+fn from_pem(text: &str, passphrase: Option<&[u8]>) -> Result<PKey<Private>, KeyError> {
+    let block = pem::private_key(text)?;
+    let pem = block.to_pem();
+    match (block.is_encrypted(), passphrase) {
+        (false, None) if is_encrypted_pkcs8(&block.contents) => Err(KeyError::MissingPassphrase),
+        (false, _) => PKey::private_key_from_pem(pem.as_bytes()).map_err(KeyError::Unparsable),
+        (true, None) => Err(KeyError::MissingPassphrase),
+        (true, Some(passphrase)) => {
+            PKey::private_key_from_pem_passphrase(pem.as_bytes(), passphrase)
+                .map_err(KeyError::Undecryptable)
+        }
+    }
+}
+
+fn from_der(der: &[u8], passphrase: Option<&[u8]>) -> Result<PKey<Private>, KeyError> {
+    let error = match PKey::private_key_from_der(der) {
+        Ok(pkey) => return Ok(pkey),
+        Err(error) => error,
+    };
+    // Only PKCS#8 may be encrypted in DER
+    match passphrase {
+        Some(passphrase) => PKey::private_key_from_pkcs8_passphrase(der, passphrase)
+            .map_err(KeyError::Undecryptable),
+        None if is_encrypted_pkcs8(der) => Err(KeyError::MissingPassphrase),
+        None => Err(KeyError::Unparsable(error)),
+    }
+}
+
+/// Tells whether DER data looks like an encrypted PKCS#8 key, i.e. a sequence
+/// starting with the encryption algorithm sequence. Unencrypted keys start
+/// with their version integer instead
+fn is_encrypted_pkcs8(der: &[u8]) -> bool {
+    let header_len = match der {
+        [0x30, len, ..] if *len < 0x80 => 2,
+        [0x30, len, ..] => 2 + usize::from(len & 0x7f),
+        _ => return false,
+    };
+    der.get(header_len) == Some(&0x30)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, KeyError};
+    use crate::common::pem::Block;
+    use crate::common::testing::generate_key;
+    use openssl::{rsa::Rsa, symm::Cipher};
+
+    #[test]
+    fn normalize_keys() {
+        let ec_key = generate_key();
+        let sec1 =
+            String::from_utf8(ec_key.ec_key().unwrap().private_key_to_pem().unwrap()).unwrap();
+        let inputs = vec![
+            ec_key.private_key_to_pem_pkcs8().unwrap(),
+            ec_key.private_key_to_der().unwrap(),
+            sec1.replace('\n', "\r\n").into_bytes(),
+        ];
+        for input in inputs {
+            assert_eq!(sec1, normalize(&input, None).unwrap());
+        }
+
+        let rsa_key = Rsa::generate(2048).unwrap();
+        let pkcs1 = String::from_utf8(rsa_key.private_key_to_pem().unwrap()).unwrap();
+        let encrypted = rsa_key
+            .private_key_to_pem_passphrase(Cipher::aes_128_cbc(), b"secret")
+            .unwrap();
+        assert_eq!(pkcs1, normalize(&encrypted, Some(b"secret")).unwrap());
+        assert!(matches!(
+            normalize(&encrypted, None),
+            Err(KeyError::MissingPassphrase)
+        ));
+        assert!(matches!(
+            normalize(&encrypted, Some(b"wrong")),
+            Err(KeyError::Undecryptable(_))
+        ));
+    }
+
+    #[test]
+    fn normalize_encrypted_pkcs8_der() {
+        let ec_key = generate_key();
+        let sec1 =
+            String::from_utf8(ec_key.ec_key().unwrap().private_key_to_pem().unwrap()).unwrap();
+        let der = ec_key
+            .private_key_to_pkcs8_passphrase(Cipher::aes_256_cbc(), b"secret")
+            .unwrap();
+        assert_eq!(sec1, normalize(&der, Some(b"secret")).unwrap());
+        assert!(matches!(
+            normalize(b"not a key", None),
+            Err(KeyError::Unparsable(_))
+        ));
+    }
+
+    #[test]
+    fn detect_encrypted_keys_without_passphrase() {
+        let ec_key = generate_key();
+        let der = ec_key
+            .private_key_to_pkcs8_passphrase(Cipher::aes_256_cbc(), b"secret")
+            .unwrap();
+        assert!(matches!(
+            normalize(&der, None),
+            Err(KeyError::MissingPassphrase)
+        ));
+        // Encrypted contents under the label of unencrypted keys
+        let pem = Block::new("PRIVATE KEY", der).to_pem();
+        assert!(matches!(
+            normalize(pem.as_bytes(), None),
+            Err(KeyError::MissingPassphrase)
+        ));
+        assert!(matches!(
+            normalize(&ec_key.private_key_to_der().unwrap()[1..], None),
+            Err(KeyError::Unparsable(_))
+        ));
+    }
+}
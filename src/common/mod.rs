@@ -1,7 +1,58 @@
-use anyhow::anyhow;
-use openssl::{nid::Nid, x509::X509};
-use std::collections::HashSet;
+mod chain;
+mod compat;
+mod key;
+pub mod pem;
+mod redacted;
+#[cfg(test)]
+pub(crate) mod testing;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use openssl::{
+    asn1::{Asn1Time, Asn1TimeRef},
+    error::ErrorStack,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey},
+    x509::{X509NameRef, X509},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::net::IpAddr;
+
+pub use chain::ChainError;
+pub use compat::{Compatibility, Issue};
+pub use key::KeyError;
+pub use pem::PemError;
+pub(crate) use redacted::read_secret;
+pub use redacted::Redacted;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct TlsRootConfig {
+    tls: Option<TlsConfig>,
+}
+
+/// How certificates are read, whatever their source
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// Takes the Common Name as the domain of certificates having no DNS
+    /// Subject Alternative Name. Off by default, as clients ignore it
+    common_name_fallback: Option<bool>,
+}
+
+impl TlsConfig {
+    /// Get config from file
+    pub fn parse(config_str: &str) -> anyhow::Result<Self> {
+        let config: TlsRootConfig = serde_yaml::from_str(config_str)?;
+        debug!("TLS config : {:?}", config);
+        Ok(config.tls.unwrap_or_default())
+    }
+
+    fn common_name_fallback(&self) -> bool {
+        self.common_name_fallback.unwrap_or(false)
+    }
+}
 
 /// Represents a TLS certificate packaged with its key and CA chain
 ///
@@ -10,13 +61,244 @@ use std::fmt;
 /// * `domains` - A list of domains related to this certificate
 /// * `key` - The certificate private key
 /// * `chain` - The certificate CA chain
-/// * `domains` - Subject Alternatives Names
-#[derive(Debug, Default)]
+/// * `domains` - DNS Subject Alternatives Names, or the Common Name when
+///   there is none and `TlsConfig` falls back to it. The Common Name comes
+///   first when it is one of them
+/// * `ip_addresses` - IP address Subject Alternative Names
+/// * `uris` - URI Subject Alternative Names
+/// * `info` - Details of the leaf certificate, when it has been parsed
+#[derive(Clone, Default)]
 pub struct TLS {
     pub cert: String,
     pub key: String,
     pub chain: Vec<String>,
     pub domains: Vec<String>,
+    pub ip_addresses: Vec<String>,
+    pub uris: Vec<String>,
+    pub info: Option<CertificateInfo>,
+}
+
+/// Errors making a certificate unfit for publishing
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("unable to parse certificate or private key of {id} : {source}")]
+    Unparsable { id: String, source: ErrorStack },
+    #[error("private key of {id} does not match its certificate")]
+    KeyMismatch { id: String },
+    #[error("certificate of {id} has no DNS name, see tls.common_name_fallback")]
+    NoDomain { id: String },
+}
+
+/// The algorithm of a certificate public key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyAlgorithm {
+    Rsa,
+    Ec,
+    Ed25519,
+    Other,
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyAlgorithm::Rsa => write!(f, "RSA"),
+            KeyAlgorithm::Ec => write!(f, "EC"),
+            KeyAlgorithm::Ed25519 => write!(f, "Ed25519"),
+            KeyAlgorithm::Other => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Details of a parsed x509 leaf certificate
+///
+/// # Fields
+///
+/// * `not_before` - Start of the validity window
+/// * `not_after` - End of the validity window
+/// * `issuer` - Distinguished name of the issuer, e.g. `CN=R3, O=Let's Encrypt`
+/// * `serial` - Serial number, upper case hex encoded
+/// * `fingerprint` - SHA-256 fingerprint of the DER certificate, lower case
+///   hex encoded
+/// * `key_algorithm` - Algorithm of the public key
+/// * `key_size` - Size of the public key in bits, e.g. 2048 or 256
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateInfo {
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    pub issuer: String,
+    pub serial: String,
+    pub fingerprint: String,
+    pub key_algorithm: KeyAlgorithm,
+    pub key_size: u32,
+}
+
+impl fmt::Display for CertificateInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "serial {} issued by {}, {} {} bits, valid from {} to {}",
+            self.serial,
+            self.issuer,
+            self.key_algorithm,
+            self.key_size,
+            self.not_before.to_rfc3339(),
+            self.not_after.to_rfc3339()
+        )
+    }
+}
+
+impl CertificateInfo {
+    /// Extracts the details of a x509 certificate
+    pub fn from_x509(x509: &X509) -> anyhow::Result<Self> {
+        let digest = x509.digest(MessageDigest::sha256())?;
+        let public_key = x509.public_key()?;
+        let key_algorithm = match public_key.id() {
+            Id::RSA => KeyAlgorithm::Rsa,
+            Id::EC => KeyAlgorithm::Ec,
+            Id::ED25519 => KeyAlgorithm::Ed25519,
+            _ => KeyAlgorithm::Other,
+        };
+        Ok(CertificateInfo {
+            not_before: asn1_time_to_utc(x509.not_before())?,
+            not_after: asn1_time_to_utc(x509.not_after())?,
+            issuer: name_to_string(x509.issuer_name())?,
+            serial: x509.serial_number().to_bn()?.to_hex_str()?.to_string(),
+            fingerprint: digest.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            key_algorithm,
+            key_size: public_key.bits(),
+        })
+    }
+
+    /// Tells whether the certificate is expired at `now`, or will be within
+    /// the given `margin`
+    pub fn expires_within(&self, now: DateTime<Utc>, margin: Duration) -> bool {
+        self.not_after <= now + margin
+    }
+}
+
+/// Identifies the object a certificate comes from in its source
+///
+/// # Fields
+///
+/// * `namespace` - The namespace of the object, if the source has any
+/// * `name` - The name of the object
+/// * `uid` - The unique identifier of this instance of the object
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectRef {
+    pub namespace: Option<String>,
+    pub name: String,
+    pub uid: Option<String>,
+}
+
+impl fmt::Display for ObjectRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.namespace {
+            Some(ref namespace) => write!(f, "{}/{}", namespace, self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl ObjectRef {
+    /// Stable identifier of the object, `namespace/name` or `name`. Unlike
+    /// the uid, it survives the object being recreated
+    pub fn id(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// Describes where a certificate comes from
+///
+/// # Fields
+///
+/// * `source` - The name of the source which emitted the certificate
+/// * `object` - The object holding the certificate in the source
+/// * `resource_version` - The version of the object, if the source has any
+/// * `labels` - The labels of the object
+/// * `annotations` - The annotations of the object
+/// * `observed_at` - When the source read the object
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub source: String,
+    pub object: ObjectRef,
+    pub resource_version: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+    pub observed_at: DateTime<Utc>,
+}
+
+impl Metadata {
+    /// Creates metadata without version, labels nor annotations, observed now
+    pub fn new(source: String, object: ObjectRef) -> Self {
+        Self {
+            source,
+            object,
+            resource_version: None,
+            labels: BTreeMap::new(),
+            annotations: BTreeMap::new(),
+            observed_at: Utc::now(),
+        }
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} from {}", self.object, self.source)?;
+        if let Some(ref resource_version) = self.resource_version {
+            write!(f, " (version {})", resource_version)?;
+        }
+        Ok(())
+    }
+}
+
+/// A certificate along with the metadata of its source object, as passed
+/// from sources to destinations
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub tls: TLS,
+    pub metadata: Metadata,
+}
+
+impl Envelope {
+    pub fn new(tls: TLS, metadata: Metadata) -> Self {
+        Self { tls, metadata }
+    }
+
+    /// Stable identifier of the source object
+    pub fn id(&self) -> String {
+        self.metadata.object.id()
+    }
+
+    /// Checks that the certificate can be published, i.e. that it has a
+    /// domain and that its private key matches its public key
+    pub fn validate(&self) -> Result<(), TlsError> {
+        if self.tls.domains.is_empty() {
+            return Err(TlsError::NoDomain { id: self.id() });
+        }
+        match self.tls.key_matches() {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(TlsError::KeyMismatch { id: self.id() }),
+            Err(source) => Err(TlsError::Unparsable {
+                id: self.id(),
+                source,
+            }),
+        }
+    }
+}
+
+/// Shows everything but the private key
+impl fmt::Debug for TLS {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TLS")
+            .field("cert", &self.cert)
+            .field("key", &Redacted::new(()))
+            .field("chain", &self.chain)
+            .field("domains", &self.domains)
+            .field("ip_addresses", &self.ip_addresses)
+            .field("uris", &self.uris)
+            .field("info", &self.info)
+            .finish()
+    }
 }
 
 impl fmt::Display for TLS {
@@ -32,146 +314,381 @@ impl TLS {
             key,
             chain,
             domains,
+            ip_addresses: vec![],
+            uris: vec![],
+            info: None,
         }
     }
 
     /// Considers the `cert` parameter to be a x509 PEM encoded certificate and
-    /// resolves the related domains with openssl
+    /// resolves the related domains and certificate details with openssl
     ///
     /// # Arguments
     ///
     /// * `cert` - The x509 PEM encoded certificate
     /// * `key` - The certificate key
     /// * `chain` - The certificate CA chain
+    /// * `config` - Whether to fall back to the Common Name for the domains
     ///
     /// # Errors
     ///
     /// This method may return an error if it is unable to parse the cert as a
     /// x509 PEM certificate
     ///
-    pub fn from_pem(cert: String, key: String, chain: Vec<String>) -> anyhow::Result<Self> {
+    pub fn from_pem(
+        cert: String,
+        key: String,
+        chain: Vec<String>,
+        config: &TlsConfig,
+    ) -> anyhow::Result<Self> {
         let x509 = X509::from_pem(cert.as_bytes())?;
-        let mut domains: HashSet<String> = HashSet::new();
-        // domains.push(x509.subject_name().entries_by_nid(Nid::COMMONNAME).next().unwrap().data().as_utf8());
-        let common_name = TLS::get_common_name_from_x509(&x509)?;
-        domains.insert(common_name);
-        if let Some(alt_names) = x509.subject_alt_names() {
-            alt_names.iter().for_each(|name| {
-                if let Some(domain) = name.dnsname() {
-                    domains.insert(String::from(domain));
-                };
-            });
-        };
-        Ok(TLS::new(cert, key, chain, domains.into_iter().collect()))
-    }
-
-    /// Tries to extract certs from String into a Vec of cert
-    pub fn split_to_vec(certs: String) -> anyhow::Result<Vec<String>> {
-        let mut ret: Vec<String> = vec![];
-        let mut loop_count = 0;
-        let mut last = 0;
-        for (index, _matched) in certs.match_indices("-----BEGIN CERTIFICATE-----") {
-            if loop_count == 0 {
-                loop_count += 1;
-                last = index;
-                continue;
+        let mut domains: Vec<String> = vec![];
+        let mut ip_addresses: Vec<String> = vec![];
+        let mut uris: Vec<String> = vec![];
+        for name in x509.subject_alt_names().iter().flatten() {
+            if let Some(domain) = name.dnsname() {
+                push_unique(&mut domains, String::from(domain));
+            } else if let Some(ip_address) = name.ipaddress().and_then(ip_address_to_string) {
+                push_unique(&mut ip_addresses, ip_address);
+            } else if let Some(uri) = name.uri() {
+                push_unique(&mut uris, String::from(uri));
             }
-            ret.push(String::from(&certs[last..index]));
-            last = index;
         }
-        ret.push(String::from(&certs[last..]));
-        Ok(ret)
+        if let Some(common_name) = TLS::get_common_name_from_x509(&x509)? {
+            match domains.iter().position(|domain| *domain == common_name) {
+                Some(index) => {
+                    let common_name = domains.remove(index);
+                    domains.insert(0, common_name);
+                }
+                None if domains.is_empty() && config.common_name_fallback() => {
+                    domains.push(common_name)
+                }
+                None => {}
+            }
+        }
+        let info = CertificateInfo::from_x509(&x509)?;
+        Ok(TLS {
+            ip_addresses,
+            uris,
+            info: Some(info),
+            ..TLS::new(cert, key, chain, domains)
+        })
+    }
+
+    /// Builds the TLS out of the PEM encoded certificates of a certificate
+    /// file and of an optional CA file, see `chain::build_chain`
+    ///
+    /// # Errors
+    ///
+    /// This method may return an error if a certificate cannot be parsed or
+    /// if the certificates do not form a valid chain
+    ///
+    pub fn from_pem_chain(
+        certs: Vec<String>,
+        key: String,
+        ca: Vec<String>,
+        config: &TlsConfig,
+    ) -> anyhow::Result<Self> {
+        let parse = |pems: Vec<String>| -> anyhow::Result<Vec<X509>> {
+            pems.iter()
+                .filter(|pem| !pem.trim().is_empty())
+                .map(|pem| Ok(X509::from_pem(pem.as_bytes())?))
+                .collect()
+        };
+        let (leaf, chain) = chain::build_chain(parse(certs)?, parse(ca)?)?;
+        let to_pem =
+            |cert: &X509| -> anyhow::Result<String> { Ok(String::from_utf8(cert.to_pem()?)?) };
+        let chain = chain
+            .iter()
+            .map(to_pem)
+            .collect::<anyhow::Result<Vec<String>>>()?;
+        TLS::from_pem(to_pem(&leaf)?, key, chain, config)
+    }
+
+    /// Converts a PEM or DER private key, possibly encrypted with
+    /// `passphrase`, to the PEM encoding accepted by ACM
+    pub fn normalize_key(key: &[u8], passphrase: Option<&[u8]>) -> Result<String, KeyError> {
+        key::normalize(key, passphrase)
+    }
+
+    /// Tells whether the private key is the counterpart of the certificate
+    /// public key
+    ///
+    /// # Errors
+    ///
+    /// This method may return an error if it is unable to parse the cert as a
+    /// x509 PEM certificate or the key as a PEM private key
+    ///
+    pub fn key_matches(&self) -> Result<bool, ErrorStack> {
+        let x509 = X509::from_pem(self.cert.as_bytes())?;
+        let key = PKey::private_key_from_pem(self.key.as_bytes())?;
+        Ok(x509.public_key()?.public_eq(&key))
+    }
+
+    /// The SHA-256 fingerprint of the certificate, if it has been parsed
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.info.as_ref().map(|info| info.fingerprint.as_str())
     }
 
     /// Extract the COMMON_NAME entry from a x509 certificate
-    fn get_common_name_from_x509(x509: &X509) -> anyhow::Result<String> {
-        Ok(String::from(std::str::from_utf8(
-            x509.subject_name()
-                .entries_by_nid(Nid::COMMONNAME)
-                .next()
-                .ok_or_else(|| anyhow!("Unable to get X509 entry COMMON_NAME"))?
-                .data()
-                .as_utf8()?
-                .as_bytes(),
-        )?))
+    fn get_common_name_from_x509(x509: &X509) -> anyhow::Result<Option<String>> {
+        match x509.subject_name().entries_by_nid(Nid::COMMONNAME).next() {
+            Some(entry) => Ok(Some(entry.data().to_string()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Appends a value unless already present, keeping the certificate order
+fn push_unique(values: &mut Vec<String>, value: String) {
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
+
+/// Formats the raw bytes of an IP address Subject Alternative Name
+fn ip_address_to_string(bytes: &[u8]) -> Option<String> {
+    let ip_address: IpAddr = match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok()?.into(),
+        16 => <[u8; 16]>::try_from(bytes).ok()?.into(),
+        _ => return None,
+    };
+    Some(ip_address.to_string())
+}
+
+/// Converts an ASN.1 time to a UTC date time
+fn asn1_time_to_utc(time: &Asn1TimeRef) -> anyhow::Result<DateTime<Utc>> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    let seconds = i64::from(diff.days) * 86400 + i64::from(diff.secs);
+    Utc.timestamp_opt(seconds, 0)
+        .single()
+        .ok_or_else(|| anyhow::anyhow!("Time {} is out of range", time))
+}
+
+/// Formats a x509 name as a distinguished name, e.g. `CN=R3, O=Let's Encrypt`
+fn name_to_string(name: &X509NameRef) -> anyhow::Result<String> {
+    let mut entries: Vec<String> = vec![];
+    for entry in name.entries() {
+        entries.push(format!(
+            "{}={}",
+            entry.object().nid().short_name()?,
+            entry.data().to_string()?
+        ));
     }
+    Ok(entries.join(", "))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::TLS;
+    use super::{Envelope, KeyAlgorithm, Metadata, ObjectRef, TlsConfig, TlsError, TLS};
+    use crate::common::testing::{certificate, generate_key, to_pem, with_alt_names};
+    use chrono::{Duration, TimeZone, Utc};
     use indoc::indoc;
+    use openssl::pkey::{PKey, Private};
+
+    const EXAMPLE_ORG_PEM: &str = indoc!(
+        "
+        -----BEGIN CERTIFICATE-----
+        MIIEszCCApsCFHeEcnKQaAxMFhiVcMiafYNXC0SmMA0GCSqGSIb3DQEBCwUAMBYx
+        FDASBgNVBAMMC2V4YW1wbGUub3JnMB4XDTIwMTAxOTE4NTQwMFoXDTIxMTAxOTE4
+        NTQwMFowFjEUMBIGA1UEAwwLZXhhbXBsZS5vcmcwggIiMA0GCSqGSIb3DQEBAQUA
+        A4ICDwAwggIKAoICAQC9D3efsRwrqUz17mgXjDI3TsDcmAR5k+WnfnI5K4CZvHLx
+        R8ueBJj5tTp3bxKv9BBAFY3JDyvyKvS1WEFq60+rAxOfz5t4NxeUlU8fF0nUFr1f
+        mzwLg+0ivbrg6vKPGMMaOQmOrSAzNV+O5B2Zh2QrA2Bq/ApWPQL5OaTsfasCIn9/
+        2/INuhQt7UcpnrD5I21Qcbn/koeZmuH26eOWMXVPbPqwgFRZZ4Z3fpoPPba78I42
+        Xp95Xk/TLo8x2FS2dm7qXwDAjaI6txHejHCm0U/xL7kgbzYutXXyNXLfP8n32AUD
+        f1oIxFYKydOaQ2nPtxF79J0UOU07ZdnWOX0kQ1hINf03O6Jy187LCbhqffAQuybN
+        sKGFJFxJGdVxv1Rb+3WoGCsyY2h/V1949o1BZM5UuNJwKW6S0/v1beM7xEAMP3KE
+        luBXmBybHnXcjQb2h/3PRdfvjyotpiB9y+72v8YKnADGmyyog/dvHtrkxrHxmabr
+        iJiCPwyYTfO8Rj1DK0AOgAPqgA2wfl/YyfryyskHi7HPXsK3Tw09n6cOTSdJ9Hem
+        G8jayw1dwZDRAhjEKN/kjVOZOPyhjv298RS4djxSO9J9R4Cl/D+LgOtjsNSVJsK1
+        NiRCaPgnqx/RuLO02WaAIglp8rtZtYMDwFHrXjohNmTdcx+2T6UYXpgcz4KSWwID
+        AQABMA0GCSqGSIb3DQEBCwUAA4ICAQB9GB56u6Wij85K/wpDqcKB83UIKc4Po91W
+        At6x3BQMCHiCE8qQ/bn/PXE6VCk/74duKwIjVMKXHJuyNwhNMiDow9Tu9WbXDByY
+        ZcWpCftoMiP5/SmPwIdk2xgsDcJruTV/iXCF25bpq8nvT7OmHKhmMa4IHnQ3wdzf
+        CZSdkisHjbMMGG2z12kLoooDjcvrzGjDOPR1YCG5cwewyeOBpgeBHKHVNnU/W9kz
+        KPMEcM0mXbYLTHlxYNjkaNKvQ3JUlR7a0aHWaLEcJVmJvLAu9vEXUnLcDhtMjK1K
+        bGv9PAc+8ATS4IqRAw5bBOmMtJ9Zf6gjs/wAcQYfQrHIMVrgGMaUIcMyG/RbRav+
+        7ZfHyi8c4SwcBV/1Q+YUM/BAtcZ6sTiNSz/iynIJETRan7/F/mUKrLAcFarUU+tC
+        5C/7UR7gUWn6rMS4Y02cGsalsCg1Ycu1ykhTQyfNiXR6EZrIWvkc22u4giNROWzC
+        Mu5UqTqGzcIq2bxbfNT1P6F7ly80Sl8Zp4Cymmj18OY720SAq0a5OXUqRU6Wtnru
+        OmknsLLODqcycNZqFeItqStaoKVb45VEJkIW9911vZlTLM5suy85oqpWJsyJnCxh
+        hyb9SOUlKyDo+dUtAFsOQTfjzYjYlhTd4kFTQXco9KybFwIBwQ1c324LOOR/xqPU
+        qMO0ZEBWgA==
+        -----END CERTIFICATE-----
+        "
+    );
 
     #[test]
     fn parse_cert_pem() -> anyhow::Result<()> {
-        let cert_pem = indoc!(
-            "
-            -----BEGIN CERTIFICATE-----
-            MIIEszCCApsCFHeEcnKQaAxMFhiVcMiafYNXC0SmMA0GCSqGSIb3DQEBCwUAMBYx
-            FDASBgNVBAMMC2V4YW1wbGUub3JnMB4XDTIwMTAxOTE4NTQwMFoXDTIxMTAxOTE4
-            NTQwMFowFjEUMBIGA1UEAwwLZXhhbXBsZS5vcmcwggIiMA0GCSqGSIb3DQEBAQUA
-            A4ICDwAwggIKAoICAQC9D3efsRwrqUz17mgXjDI3TsDcmAR5k+WnfnI5K4CZvHLx
-            R8ueBJj5tTp3bxKv9BBAFY3JDyvyKvS1WEFq60+rAxOfz5t4NxeUlU8fF0nUFr1f
-            mzwLg+0ivbrg6vKPGMMaOQmOrSAzNV+O5B2Zh2QrA2Bq/ApWPQL5OaTsfasCIn9/
-            2/INuhQt7UcpnrD5I21Qcbn/koeZmuH26eOWMXVPbPqwgFRZZ4Z3fpoPPba78I42
-            Xp95Xk/TLo8x2FS2dm7qXwDAjaI6txHejHCm0U/xL7kgbzYutXXyNXLfP8n32AUD
-            f1oIxFYKydOaQ2nPtxF79J0UOU07ZdnWOX0kQ1hINf03O6Jy187LCbhqffAQuybN
-            sKGFJFxJGdVxv1Rb+3WoGCsyY2h/V1949o1BZM5UuNJwKW6S0/v1beM7xEAMP3KE
-            luBXmBybHnXcjQb2h/3PRdfvjyotpiB9y+72v8YKnADGmyyog/dvHtrkxrHxmabr
-            iJiCPwyYTfO8Rj1DK0AOgAPqgA2wfl/YyfryyskHi7HPXsK3Tw09n6cOTSdJ9Hem
-            G8jayw1dwZDRAhjEKN/kjVOZOPyhjv298RS4djxSO9J9R4Cl/D+LgOtjsNSVJsK1
-            NiRCaPgnqx/RuLO02WaAIglp8rtZtYMDwFHrXjohNmTdcx+2T6UYXpgcz4KSWwID
-            AQABMA0GCSqGSIb3DQEBCwUAA4ICAQB9GB56u6Wij85K/wpDqcKB83UIKc4Po91W
-            At6x3BQMCHiCE8qQ/bn/PXE6VCk/74duKwIjVMKXHJuyNwhNMiDow9Tu9WbXDByY
-            ZcWpCftoMiP5/SmPwIdk2xgsDcJruTV/iXCF25bpq8nvT7OmHKhmMa4IHnQ3wdzf
-            CZSdkisHjbMMGG2z12kLoooDjcvrzGjDOPR1YCG5cwewyeOBpgeBHKHVNnU/W9kz
-            KPMEcM0mXbYLTHlxYNjkaNKvQ3JUlR7a0aHWaLEcJVmJvLAu9vEXUnLcDhtMjK1K
-            bGv9PAc+8ATS4IqRAw5bBOmMtJ9Zf6gjs/wAcQYfQrHIMVrgGMaUIcMyG/RbRav+
-            7ZfHyi8c4SwcBV/1Q+YUM/BAtcZ6sTiNSz/iynIJETRan7/F/mUKrLAcFarUU+tC
-            5C/7UR7gUWn6rMS4Y02cGsalsCg1Ycu1ykhTQyfNiXR6EZrIWvkc22u4giNROWzC
-            Mu5UqTqGzcIq2bxbfNT1P6F7ly80Sl8Zp4Cymmj18OY720SAq0a5OXUqRU6Wtnru
-            OmknsLLODqcycNZqFeItqStaoKVb45VEJkIW9911vZlTLM5suy85oqpWJsyJnCxh
-            hyb9SOUlKyDo+dUtAFsOQTfjzYjYlhTd4kFTQXco9KybFwIBwQ1c324LOOR/xqPU
-            qMO0ZEBWgA==
-            -----END CERTIFICATE-----
-            "
-        );
+        let cert_pem = EXAMPLE_ORG_PEM;
         let tls = TLS::from_pem(
             String::from(cert_pem),
             String::from(""),
             vec![String::from("")],
+            &TlsConfig::parse("tls: {common_name_fallback: true}")?,
         )?;
         assert_eq!(1, tls.domains.len());
         // Ugly cast, need workaround
-        assert_eq!(true, tls.domains.contains(&"example.org".to_owned()));
+        assert!(tls.domains.contains(&"example.org".to_owned()));
         // Need to check with alt names
         Ok(())
     }
 
     #[test]
-    fn split_multiple_certs_into_vec() {
-        let first_cert = indoc!(
-            "
-            -----BEGIN CERTIFICATE-----
-            MIIJVjCCCD6gAwIBAgIQAqGZwYqQRZwCAAAAAHPMbDANBgkqhkiG9w0BAQsFADBC
-            ...
-            MP6Cub32KGa5uYGUBvB4l8B88zFHTsAUBvmgr8LgFTQGgPP6gXKj2ySb
-            -----END CERTIFICATE-----
-            "
+    fn fingerprint_cert_pem() -> anyhow::Result<()> {
+        let tls = TLS::from_pem(
+            String::from(EXAMPLE_ORG_PEM),
+            String::new(),
+            vec![],
+            &TlsConfig::default(),
+        )?;
+        assert_eq!(
+            Some("86d7705b68b39ffbdc6bdb7064aacb1ce1815a9df0eab63dd7f30759cac0f744"),
+            tls.fingerprint()
+        );
+        assert_eq!(None, TLS::default().fingerprint());
+        Ok(())
+    }
+
+    #[test]
+    fn cert_info_pem() -> anyhow::Result<()> {
+        let tls = TLS::from_pem(
+            String::from(EXAMPLE_ORG_PEM),
+            String::new(),
+            vec![],
+            &TlsConfig::default(),
+        )?;
+        let info = tls.info.unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2020, 10, 19, 18, 54, 0).unwrap(),
+            info.not_before
+        );
+        assert_eq!(
+            Utc.with_ymd_and_hms(2021, 10, 19, 18, 54, 0).unwrap(),
+            info.not_after
+        );
+        assert_eq!("CN=example.org", info.issuer);
+        assert_eq!("7784727290680C4C16189570C89A7D83570B44A6", info.serial);
+        assert_eq!(KeyAlgorithm::Rsa, info.key_algorithm);
+        assert_eq!(4096, info.key_size);
+        let before_expiry = Utc.with_ymd_and_hms(2021, 10, 1, 0, 0, 0).unwrap();
+        assert!(!info.expires_within(before_expiry, Duration::zero()));
+        assert!(info.expires_within(before_expiry, Duration::days(30)));
+        Ok(())
+    }
+
+    /// Self-signs a certificate for `example.org` with the given key
+    fn self_signed_pem(key: &PKey<Private>) -> String {
+        to_pem(&certificate("example.org", key, None))
+    }
+
+    fn envelope(cert: String, key: &PKey<Private>, config: &TlsConfig) -> Envelope {
+        let key = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let object = ObjectRef {
+            namespace: Some(String::from("default")),
+            name: String::from("example-tls"),
+            uid: None,
+        };
+        Envelope::new(
+            TLS::from_pem(cert, key, vec![], config).unwrap(),
+            Metadata::new(String::from("test"), object),
+        )
+    }
+
+    #[test]
+    fn validate_key() -> anyhow::Result<()> {
+        let config = TlsConfig::parse("tls: {common_name_fallback: true}")?;
+        let key = generate_key();
+        let cert = self_signed_pem(&key);
+        assert!(envelope(cert.clone(), &key, &config).validate().is_ok());
+        let error = envelope(cert, &generate_key(), &config)
+            .validate()
+            .unwrap_err();
+        assert!(matches!(error, TlsError::KeyMismatch { ref id } if id == "default/example-tls"));
+        assert_eq!(
+            "private key of default/example-tls does not match its certificate",
+            error.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_certificate_without_domain() {
+        let key = generate_key();
+        let envelope = envelope(self_signed_pem(&key), &key, &TlsConfig::default());
+        assert!(envelope.tls.domains.is_empty());
+        assert!(matches!(
+            envelope.validate().unwrap_err(),
+            TlsError::NoDomain { ref id } if id == "default/example-tls"
+        ));
+    }
+
+    #[test]
+    fn parse_subject_alt_names() -> anyhow::Result<()> {
+        let key = generate_key();
+        let cert = with_alt_names(
+            Some("example.org"),
+            &[
+                "www.example.org",
+                "*.example.org",
+                "example.org",
+                "www.example.org",
+            ],
+            &["192.0.2.1", "2001:db8::1"],
+            &["spiffe://example.org/web"],
+            &key,
         );
-        let second_cert = indoc!(
+        let tls = TLS::from_pem(to_pem(&cert), String::new(), vec![], &TlsConfig::default())?;
+        assert_eq!(
+            vec!["example.org", "www.example.org", "*.example.org"],
+            tls.domains
+        );
+        assert_eq!(vec!["192.0.2.1", "2001:db8::1"], tls.ip_addresses);
+        assert_eq!(vec!["spiffe://example.org/web"], tls.uris);
+
+        // No CN, which is only a fallback
+        let cert = with_alt_names(None, &["b.example.org", "a.example.org"], &[], &[], &key);
+        let tls = TLS::from_pem(to_pem(&cert), String::new(), vec![], &TlsConfig::default())?;
+        assert_eq!(vec!["b.example.org", "a.example.org"], tls.domains);
+
+        let cert = with_alt_names(None, &[], &["192.0.2.1"], &[], &key);
+        let tls = TLS::from_pem(to_pem(&cert), String::new(), vec![], &TlsConfig::default())?;
+        assert!(tls.domains.is_empty());
+        assert_eq!(vec!["192.0.2.1"], tls.ip_addresses);
+        Ok(())
+    }
+
+    #[test]
+    fn common_name_fallback() -> anyhow::Result<()> {
+        let cert = to_pem(&certificate("example.org", &generate_key(), None));
+        let tls = TLS::from_pem(cert.clone(), String::new(), vec![], &TlsConfig::default())?;
+        assert!(tls.domains.is_empty());
+
+        let config = TlsConfig::parse(indoc!(
             "
-            -----BEGIN CERTIFICATE-----
-            MIIJVjCCCD6gAwIBAgIQAqGZwYqQRZwCAAAAAHPMbDANBgkqhkiG9w0BAQsFADBC
-            ...
-            MP6Cub32KGa5uYGUBvB4l8B88zFHTsAUBvmgr8LgFTQGgPP6gXKj2ySb
-            -----END CERTIFICATE-----
+            tls:
+              common_name_fallback: true
             "
-        );
-        let mut concat_certs = String::from(first_cert);
-        concat_certs.push_str(second_cert);
-        let certs = TLS::split_to_vec(concat_certs).unwrap();
-        assert_eq!(String::from(first_cert), *certs.get(0).unwrap());
-        assert_eq!(String::from(second_cert), *certs.get(1).unwrap());
+        ))?;
+        let tls = TLS::from_pem(cert, String::new(), vec![], &config)?;
+        assert_eq!(vec!["example.org"], tls.domains);
+        assert_eq!(TlsConfig::parse("aws: {}")?, TlsConfig::default());
+        Ok(())
+    }
+
+    #[test]
+    fn object_ref_id() {
+        let namespaced = ObjectRef {
+            namespace: Some(String::from("default")),
+            name: String::from("example-tls"),
+            uid: Some(String::from("1234")),
+        };
+        assert_eq!("default/example-tls", namespaced.id());
+        let cluster_wide = ObjectRef {
+            name: String::from("example-tls"),
+            ..Default::default()
+        };
+        assert_eq!("example-tls", cluster_wide.id());
     }
 }
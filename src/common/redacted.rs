@@ -0,0 +1,108 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A sensitive value, e.g. a password or an access key, which `Debug` never
+/// prints so that it cannot leak through logs
+///
+/// The value is only read through `expose`, which makes every use of it easy
+/// to spot.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Redacted<T = String>(T);
+
+impl<T> Redacted<T> {
+    pub fn new(value: T) -> Self {
+        Redacted(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> From<T> for Redacted<T> {
+    fn from(value: T) -> Self {
+        Redacted(value)
+    }
+}
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+/// Reads the sensitive setting `name`, given either inline, from the file of
+/// `<name>_file` or from the environment variable of `<name>_env`
+///
+/// Surrounding whitespace is removed from files and variables, as mounted
+/// files usually end with a newline.
+///
+/// # Errors
+///
+/// This function returns an error if none or several of the three forms are
+/// set, or if the file or the variable cannot be read
+pub fn read_secret(
+    name: &str,
+    value: Option<&Redacted>,
+    file: Option<&str>,
+    env: Option<&str>,
+) -> anyhow::Result<Redacted> {
+    match (value, file, env) {
+        (Some(value), None, None) => Ok(value.clone()),
+        (None, Some(file), None) => std::fs::read_to_string(file)
+            .map(|value| Redacted(String::from(value.trim())))
+            .map_err(|e| anyhow!("Unable to read {} from {} : {}", name, file, e)),
+        (None, None, Some(env)) => std::env::var(env)
+            .map(|value| Redacted(String::from(value.trim())))
+            .map_err(|e| anyhow!("Unable to read {} from ${} : {}", name, env, e)),
+        (None, None, None) => Err(anyhow!("One of {0}, {0}_file or {0}_env is required", name)),
+        _ => Err(anyhow!(
+            "Only one of {0}, {0}_file or {0}_env can be set",
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_secret, Redacted};
+    use std::io::Write;
+
+    #[test]
+    fn debug_redacted() {
+        let secret = Redacted::new(String::from("secret_key"));
+        assert_eq!("[redacted]", format!("{:?}", secret));
+        assert_eq!("Some([redacted])", format!("{:?}", Some(secret.clone())));
+        assert_eq!("secret_key", secret.expose());
+        let parsed: Redacted = serde_yaml::from_str("secret_key").unwrap();
+        assert_eq!(parsed, secret);
+    }
+
+    #[test]
+    fn read_secret_forms() -> anyhow::Result<()> {
+        let inline = Redacted::new(String::from("inline"));
+        assert_eq!(
+            "inline",
+            read_secret("secret_key", Some(&inline), None, None)?.expose()
+        );
+
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "from_file")?;
+        let path = file.path().to_string_lossy();
+        assert_eq!(
+            "from_file",
+            read_secret("secret_key", None, Some(&path), None)?.expose()
+        );
+
+        std::env::set_var("CERT_SYNC_TEST_SECRET_KEY", "from_env");
+        let value = read_secret("secret_key", None, None, Some("CERT_SYNC_TEST_SECRET_KEY"))?;
+        assert_eq!("from_env", value.expose());
+
+        assert!(read_secret("secret_key", None, None, None).is_err());
+        assert!(read_secret("secret_key", Some(&inline), Some(&path), None).is_err());
+        assert!(read_secret("secret_key", None, Some("/nonexistent"), None).is_err());
+        Ok(())
+    }
+}
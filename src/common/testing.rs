@@ -0,0 +1,104 @@
+//! Certificates generated on the fly for tests
+
+use openssl::{
+    asn1::Asn1Time,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    x509::{
+        extension::{BasicConstraints, SubjectAlternativeName},
+        X509NameBuilder, X509,
+    },
+};
+
+pub fn generate_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+/// Creates a certificate for `name`, self-signed when `issuer` is `None`.
+/// Names other than `example.org` are CA certificates
+pub fn certificate(
+    name: &str,
+    key: &PKey<Private>,
+    issuer: Option<(&X509, &PKey<Private>)>,
+) -> X509 {
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+    let subject = subject.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(90).unwrap())
+        .unwrap();
+    if name != "example.org" {
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+    }
+    match issuer {
+        Some((issuer, issuer_key)) => {
+            builder.set_issuer_name(issuer.subject_name()).unwrap();
+            builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+        }
+        None => {
+            builder.set_issuer_name(&subject).unwrap();
+            builder.sign(key, MessageDigest::sha256()).unwrap();
+        }
+    }
+    builder.build()
+}
+
+/// Self-signs a leaf certificate with the given Subject Alternative Names
+pub fn with_alt_names(
+    common_name: Option<&str>,
+    dns_names: &[&str],
+    ip_addresses: &[&str],
+    uris: &[&str],
+    key: &PKey<Private>,
+) -> X509 {
+    let mut subject = X509NameBuilder::new().unwrap();
+    match common_name {
+        Some(common_name) => subject.append_entry_by_nid(Nid::COMMONNAME, common_name),
+        None => subject.append_entry_by_nid(Nid::ORGANIZATIONNAME, "Example"),
+    }
+    .unwrap();
+    let subject = subject.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    builder.set_issuer_name(&subject).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(90).unwrap())
+        .unwrap();
+    let mut alt_names = SubjectAlternativeName::new();
+    for dns_name in dns_names {
+        alt_names.dns(dns_name);
+    }
+    for ip_address in ip_addresses {
+        alt_names.ip(ip_address);
+    }
+    for uri in uris {
+        alt_names.uri(uri);
+    }
+    let alt_names = alt_names
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(alt_names).unwrap();
+    builder.sign(key, MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+pub fn to_pem(cert: &X509) -> String {
+    String::from_utf8(cert.to_pem().unwrap()).unwrap()
+}
@@ -5,260 +5,1520 @@ extern crate rusoto_core;
 use anyhow::anyhow;
 use async_trait::async_trait;
 use bytes::Bytes;
+use chrono::Utc;
 
 use hyper::client::{Client, HttpConnector};
 use hyper::Uri;
 use hyper_proxy::{Intercept, Proxy, ProxyConnector};
 use hyper_tls::HttpsConnector;
 use rusoto_acm::{
-    Acm, AcmClient, CertificateSummary, ImportCertificateRequest, ImportCertificateResponse,
-    ListCertificatesRequest, Tag,
+    Acm, AcmClient, AddTagsToCertificateRequest, CertificateSummary, DeleteCertificateRequest,
+    DescribeCertificateRequest, Filters, ImportCertificateError, ImportCertificateRequest,
+    ImportCertificateResponse, ListCertificatesRequest, ListTagsForCertificateRequest, Tag,
 };
 use rusoto_core::request::HttpClient;
-use rusoto_core::Region;
-use rusoto_credential::ChainProvider;
-use rusoto_elbv2::{AddListenerCertificatesInput, Certificate, Elb, ElbClient};
+use rusoto_core::{Region, RusotoError};
+use rusoto_elbv2::{
+    AddListenerCertificatesInput, Certificate, DescribeListenerCertificatesInput,
+    DescribeListenersInput, DescribeLoadBalancersInput, DescribeTagsInput, Elb, ElbClient,
+    RemoveListenerCertificatesInput,
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
-use super::TLS;
+use super::aws_credentials::{AcmAlbCredentials, AuthConfig, CredentialsProvider};
+use super::{into_result, Compatibility, Destination, Envelope, PublishError, Published};
+use crate::throttle::{RateLimitConfig, RetryConfig, SystemClock, Throttle};
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct AwsRootConfig {
     aws: AcmAlbConfig,
 }
 
+/// The settings shared by all targets, along with either the `targets` or
+/// the settings of a single target
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct AcmAlbConfig {
+    region: Option<Region>,
+    credentials: Option<AcmAlbCredentials>,
+    auth: Option<AuthConfig>,
+    load_balancers: Option<Vec<String>>,
+    identity_tag: Option<String>,
+    /// Name of this cert-sync deployment, telling its certificates from the
+    /// ones of other deployments sharing the account
+    instance: Option<String>,
+    dry_run: Option<bool>,
+    compatibility: Option<CompatibilityPolicy>,
+    /// Rate limit of the AWS calls, applied to each target on its own
+    rate_limit: Option<RateLimitConfig>,
+    /// Retries of the AWS calls failing with throttling or server errors
+    retry: Option<RetryConfig>,
+    targets: Option<Vec<AcmAlbTargetConfig>>,
+}
+
+/// An account and region certificates are imported into
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct AcmAlbTargetConfig {
+    /// Unique name, defaults to `account/region` or `region`
+    name: Option<String>,
+    #[serde(deserialize_with = "deserialize_region")]
     region: Region,
+    /// Only the listeners of this account are used when set
+    account: Option<String>,
+    /// Static access keys, short for the `static` mode of `auth`
     credentials: Option<AcmAlbCredentials>,
+    auth: Option<AuthConfig>,
     load_balancers: Option<Vec<String>>,
+    filters: Option<TargetFilters>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct AcmAlbCredentials {
-    access_key: String,
-    secret_key: String,
+/// Selects the certificates imported into a target, all of them by default
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct TargetFilters {
+    /// At least one domain of the certificate must match one of these, where
+    /// `*.example.org` matches any subdomain of `example.org`
+    domains: Option<Vec<String>>,
+    /// Annotations the source object must carry with these values
+    annotations: Option<BTreeMap<String, String>>,
 }
 
+impl TargetFilters {
+    fn matches(&self, envelope: &Envelope) -> bool {
+        let domains = match self.domains {
+            Some(ref patterns) => patterns.iter().any(|pattern| {
+                envelope
+                    .tls
+                    .domains
+                    .iter()
+                    .any(|domain| domain_matches(pattern, domain))
+            }),
+            None => true,
+        };
+        let annotations = self
+            .annotations
+            .iter()
+            .flatten()
+            .all(|(key, value)| envelope.metadata.annotations.get(key) == Some(value));
+        domains && annotations
+    }
+}
+
+/// What to do with certificates ACM or ALB would reject
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CompatibilityPolicy {
+    /// Neither import nor attach them
+    Refuse,
+    /// Log a warning and try to import and attach them anyway
+    Warn,
+    /// Import them into ACM without attaching them to listeners, as long as
+    /// ACM accepts them
+    ImportOnly,
+}
+
+/// Tag key holding the identifier of the source object, unless overridden
+/// with `identity_tag`
+const DEFAULT_IDENTITY_TAG: &str = "Source";
+
+/// Tag key holding the SHA-256 fingerprint of the imported certificate
+const FINGERPRINT_TAG: &str = "Fingerprint";
+
+/// Tag key holding the name of the cert-sync deployment which imported the
+/// certificate
+const INSTANCE_TAG: &str = "Instance";
+
+/// Tag key holding the name of the target which imported the certificate
+const TARGET_TAG: &str = "Target";
+
+/// Name of the cert-sync deployment, unless overridden with `instance`
+const DEFAULT_INSTANCE: &str = "default";
+
+/// Annotation listing the ARNs of the listeners to attach a certificate to,
+/// comma separated
+const LISTENERS_ANNOTATION: &str = "cert-sync.io/alb-listeners";
+
+/// Annotation selecting the load balancers whose HTTPS listeners a
+/// certificate is attached to, by tags formatted as `key=value,key=value`
+const SELECTOR_ANNOTATION: &str = "cert-sync.io/alb-selector";
+
+/// ARN reported in dry-run mode for certificates that would be created
+const UNKNOWN_ARN: &str = "(known after import)";
+
+/// Key types of the certificates to list, ACM only lists `RSA_2048` ones by
+/// default
+const ACM_KEY_TYPES: [&str; 7] = [
+    "RSA_1024",
+    "RSA_2048",
+    "RSA_3072",
+    "RSA_4096",
+    "EC_prime256v1",
+    "EC_secp384r1",
+    "EC_secp521r1",
+];
+
+/// Imports certificates into ACM and attaches them to ALB listeners, in one
+/// or several accounts and regions
+///
+/// Each certificate is imported into every target whose filters it matches.
+/// A failing target does not prevent the others from receiving the
+/// certificate, the failures are reported per target once all are done.
 pub struct AcmAlbDestination {
-    config: AcmAlbConfig,
+    targets: Vec<AcmAlbTarget>,
+}
+
+/// An account and region of the destination, with its own clients and
+/// listeners
+struct AcmAlbTarget {
+    name: String,
+    region: Region,
+    account: Option<String>,
+    load_balancers: Vec<String>,
+    filters: TargetFilters,
     acm_client: AcmClient,
     elb_client: ElbClient,
+    /// Rate limits and retries the calls to both clients
+    throttle: Throttle,
     tag_managed_by: Tag,
+    /// Instance and target tags, telling the certificates of this target
+    /// from the ones of other deployments or targets of the account
+    scope_tags: Vec<Tag>,
+    identity_tag: String,
+    dry_run: bool,
+    compatibility: CompatibilityPolicy,
+    /// Listeners resolved from annotations, inspected when listing
+    /// certificates attachments
+    known_listeners: Mutex<BTreeSet<String>>,
+    /// Whether the listeners using the certificates of the target have been
+    /// added to the known ones, which is done on the first listing
+    listeners_discovered: AtomicBool,
+    /// Tags of the ACM certificates, refilled when listing the certificates
+    /// of the target and kept up to date with its own changes
+    cert_tags: Mutex<TagCache>,
+}
+
+/// Tags of ACM certificates by ARN, sparing a `ListTagsForCertificate` call
+/// per certificate each time a source object is published
+#[derive(Debug, Default)]
+struct TagCache {
+    tags: HashMap<String, Vec<Tag>>,
+}
+
+impl TagCache {
+    fn get(&self, cert_arn: &str) -> Option<Vec<Tag>> {
+        self.tags.get(cert_arn).cloned()
+    }
+
+    fn insert(&mut self, cert_arn: &str, tags: Vec<Tag>) {
+        self.tags.insert(String::from(cert_arn), tags);
+    }
+
+    /// Adds or overwrites tags of a known certificate, as ACM does
+    fn merge(&mut self, cert_arn: &str, tags: &[Tag]) {
+        if let Some(known) = self.tags.get_mut(cert_arn) {
+            for tag in tags {
+                known.retain(|known| known.key != tag.key);
+                known.push(tag.clone());
+            }
+        }
+    }
+
+    fn remove(&mut self, cert_arn: &str) {
+        self.tags.remove(cert_arn);
+    }
+}
+
+/// An ACM certificate along with its tags
+struct ExistingCert {
+    arn: String,
+    tags: Vec<Tag>,
+}
+
+impl ExistingCert {
+    fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.key == key)
+            .and_then(|tag| tag.value.as_deref())
+    }
+
+    /// Tells whether another cert-sync deployment or target imported the
+    /// certificate. Certificates imported before the instance or target tags
+    /// existed may belong to any of them
+    fn is_foreign(&self, scope_tags: &[Tag]) -> bool {
+        scope_tags.iter().any(|scope| {
+            matches!(self.tag(&scope.key), Some(value) if Some(value) != scope.value.as_deref())
+        })
+    }
+
+    /// Tells whether the certificate was imported by the target of the
+    /// deployment, and may then be deleted when its source object is gone
+    fn is_owned(&self, tag_managed_by: &Tag, scope_tags: &[Tag]) -> bool {
+        self.tags.contains(tag_managed_by) && scope_tags.iter().all(|tag| self.tags.contains(tag))
+    }
+
+    /// Tells whether the certificate may be deleted along with the source
+    /// object. Certificates only matching its domains may still be used by
+    /// another source object
+    fn is_deletable(
+        &self,
+        tag_managed_by: &Tag,
+        scope_tags: &[Tag],
+        identity_tag: &str,
+        id: &str,
+    ) -> bool {
+        self.is_owned(tag_managed_by, scope_tags) && self.tag(identity_tag) == Some(id)
+    }
 }
 
 #[async_trait]
-impl super::Destination for AcmAlbDestination {
+impl Destination for AcmAlbDestination {
     fn name(&self) -> String {
         String::from("AWS ACM-ALB ")
     }
 
-    async fn publish(&self, tls: TLS) -> anyhow::Result<()> {
-        debug!("TLS domains : {:?}", tls);
-        match self.send_to_acm(tls).await {
-            Ok(cert_arn) => {
-                debug!("ACM Cert ARN : {}", cert_arn);
-                if let Some(ref listeners_arns) = self.config.load_balancers {
-                    if let Err(e) = self.link_to_alb_listeners(cert_arn, listeners_arns).await {
-                        error!("Unable to add certificate to ALB : {}", e);
-                    };
-                }
+    async fn publish(&self, envelope: Envelope) -> Result<(), PublishError> {
+        let mut errors: Vec<(String, PublishError)> = vec![];
+        for target in self.accepting(&envelope) {
+            if let Err(e) = target.publish(envelope.clone()).await {
+                error!("Unable to publish certificate to {} : {}", target.name, e);
+                errors.push((target.name.clone(), e));
             }
-            Err(e) => error!("Unable to send certificate to ACM : {}", e),
         }
-        Ok(())
+        PublishError::from_parts(errors)
+    }
+
+    /// Unpublishes from all targets, as the filters may have changed since
+    /// the certificate was published
+    async fn unpublish(&self, envelope: Envelope) -> anyhow::Result<()> {
+        let mut errors: Vec<String> = vec![];
+        for target in &self.targets {
+            if let Err(e) = target.unpublish(envelope.clone()).await {
+                error!(
+                    "Unable to unpublish certificate from {} : {}",
+                    target.name, e
+                );
+                errors.push(format!("{} : {}", target.name, e));
+            }
+        }
+        into_result(errors)
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<Published>> {
+        let mut published: Vec<Published> = vec![];
+        for target in &self.targets {
+            published.extend(target.list().await?);
+        }
+        Ok(published)
+    }
+
+    async fn targets(&self, envelope: &Envelope) -> anyhow::Result<Vec<String>> {
+        let mut listeners_arns: Vec<String> = vec![];
+        for target in self.accepting(envelope) {
+            listeners_arns.extend(target.targets(envelope).await?);
+        }
+        Ok(listeners_arns)
+    }
+
+    async fn attach(&self, published: &Published, target: &str) -> anyhow::Result<()> {
+        self.holder(published)?.attach(published, target).await
+    }
+
+    async fn detach(&self, published: &Published, target: &str) -> anyhow::Result<()> {
+        self.holder(published)?.detach(published, target).await
+    }
+
+    async fn remove(&self, published: &Published) -> anyhow::Result<()> {
+        self.holder(published)?.remove(published).await
+    }
+
+    fn accepts(&self, envelope: &Envelope) -> bool {
+        self.targets.iter().any(|target| target.accepts(envelope))
+    }
+
+    /// Reconciles each target on its own, as a certificate is published once
+    /// per target
+    fn partitions(&self) -> Vec<&dyn Destination> {
+        self.targets
+            .iter()
+            .map(|target| target as &dyn Destination)
+            .collect()
     }
 }
 
 impl AcmAlbDestination {
     pub fn new(config_str: &str) -> anyhow::Result<Self> {
         let config = parse_config(config_str)?;
-        if let Some(creds) = config.credentials.as_ref() {
-            debug!(
-                "Using credentials from config, access_key : {}, secret_key : {}",
-                creds.access_key, creds.secret_key
+        let identity_tag = config
+            .identity_tag
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_IDENTITY_TAG));
+        let instance = config
+            .instance
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_INSTANCE));
+        let dry_run = config.dry_run.unwrap_or(false);
+        let compatibility = config.compatibility.unwrap_or(CompatibilityPolicy::Warn);
+        let mut targets: Vec<AcmAlbTarget> = vec![];
+        for target_config in target_configs(&config)? {
+            let throttle = Throttle::new(
+                config.rate_limit.as_ref(),
+                config.retry.as_ref(),
+                Arc::new(SystemClock),
+            );
+            let target = AcmAlbTarget::new(
+                target_config,
+                identity_tag.clone(),
+                instance.clone(),
+                compatibility,
+                throttle,
+            )?;
+            if targets.iter().any(|other| other.name == target.name) {
+                return Err(anyhow!("Duplicate AWS target name {}", target.name));
+            }
+            targets.push(target);
+        }
+        Ok(AcmAlbDestination { targets }.with_dry_run(dry_run))
+    }
+
+    /// Enables the dry-run mode, in which mutating AWS calls are printed as
+    /// JSON lines on stdout instead of being performed. Read-only calls are
+    /// still made
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        for target in &mut self.targets {
+            target.dry_run = target.dry_run || dry_run;
+        }
+        self
+    }
+
+    /// Returns the targets whose filters select the certificate
+    fn accepting<'a>(&'a self, envelope: &'a Envelope) -> impl Iterator<Item = &'a AcmAlbTarget> {
+        self.targets
+            .iter()
+            .filter(move |target| target.accepts(envelope))
+    }
+
+    /// Finds the target holding a published certificate, by the name it was
+    /// listed with. Otherwise, the region and account of its ARN must match
+    /// a single target
+    fn holder(&self, published: &Published) -> anyhow::Result<&AcmAlbTarget> {
+        if let Some(ref name) = published.holder {
+            return self
+                .targets
+                .iter()
+                .find(|target| target.name == *name)
+                .ok_or_else(|| anyhow!("No AWS target named {}", name));
+        }
+        let mut owners = self
+            .targets
+            .iter()
+            .filter(|target| target.owns(&published.reference));
+        match (owners.next(), owners.next()) {
+            (Some(target), None) => Ok(target),
+            (Some(_), Some(_)) => Err(anyhow!(
+                "Several AWS targets may hold certificate {}",
+                published.reference
+            )),
+            (None, _) => Err(anyhow!(
+                "No AWS target holds certificate {}",
+                published.reference
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl Destination for AcmAlbTarget {
+    fn name(&self) -> String {
+        format!("AWS ACM-ALB {}", self.name)
+    }
+
+    async fn publish(&self, envelope: Envelope) -> Result<(), PublishError> {
+        info!("Publish certificate {} to {}", envelope.metadata, self.name);
+        debug!("TLS domains : {:?}", envelope.tls);
+        self.check_compatibility(&envelope)?;
+        let listeners_arns = self.targets(&envelope).await?;
+        let cert_arn = self.send_to_acm(envelope).await.map_err(|e| match e {
+            PublishError::Failed(e) => anyhow!("Unable to send certificate to ACM : {}", e).into(),
+            e => e,
+        })?;
+        debug!("ACM Cert ARN : {}", cert_arn);
+        self.link_to_alb_listeners(&cert_arn, &listeners_arns).await
+    }
+
+    async fn unpublish(&self, envelope: Envelope) -> anyhow::Result<()> {
+        debug!("TLS domains : {:?}", envelope.tls);
+        let existing_cert = match self.retrieve_existing_cert(&envelope).await? {
+            Some(cert) => cert,
+            None => {
+                info!("No ACM certificate found for {}", envelope.metadata);
+                return Ok(());
+            }
+        };
+        if !existing_cert.is_deletable(
+            &self.tag_managed_by,
+            &self.scope_tags,
+            &self.identity_tag,
+            &envelope.id(),
+        ) {
+            info!(
+                "Certificate {} was not imported by this target for {}, leave it untouched",
+                existing_cert.arn,
+                envelope.id()
+            );
+            return Ok(());
+        }
+        let listeners_arns = self.targets(&envelope).await?;
+        self.delete_certificate(&existing_cert.arn, &listeners_arns)
+            .await
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<Published>> {
+        // Tags are read again on each listing, in case they changed outside
+        // of this target
+        let mut tag_cache = TagCache::default();
+        let mut owned_certs: Vec<ExistingCert> = vec![];
+        for summary in self.list_certificates().await? {
+            let arn = match summary.certificate_arn {
+                Some(arn) => arn,
+                None => continue,
+            };
+            let tags = self.list_tags(&arn).await?;
+            tag_cache.insert(&arn, tags.clone());
+            let cert = ExistingCert { tags, arn };
+            // Certificates of other deployments are not orphans of this one
+            if cert.is_owned(&self.tag_managed_by, &self.scope_tags) {
+                owned_certs.push(cert);
+            }
+        }
+        *self.cert_tags.lock().unwrap() = tag_cache;
+        if !self.listeners_discovered.load(Ordering::SeqCst) {
+            self.discover_listeners(&owned_certs).await?;
+            self.listeners_discovered.store(true, Ordering::SeqCst);
+        }
+        let mut listeners_arns = self.known_listeners.lock().unwrap().clone();
+        listeners_arns.extend(self.load_balancers.iter().cloned());
+        let mut attachments: HashMap<String, Vec<String>> = HashMap::new();
+        for listener_arn in &listeners_arns {
+            for cert_arn in self.retrieve_listener_certs(listener_arn).await? {
+                attachments
+                    .entry(cert_arn)
+                    .or_default()
+                    .push(listener_arn.clone());
+            }
+        }
+        let mut published: Vec<Published> = vec![];
+        for cert in owned_certs {
+            if let Some(id) = cert.tag(&self.identity_tag) {
+                published.push(Published {
+                    id: String::from(id),
+                    fingerprint: cert.tag(FINGERPRINT_TAG).map(String::from),
+                    targets: attachments.remove(&cert.arn).unwrap_or_default(),
+                    reference: cert.arn,
+                    holder: Some(self.name.clone()),
+                });
+            }
+        }
+        Ok(published)
+    }
+
+    /// Resolves the listeners from the source object annotations, or falls
+    /// back to the configured ones. Annotated listeners of other accounts or
+    /// regions are left to their own target
+    async fn targets(&self, envelope: &Envelope) -> anyhow::Result<Vec<String>> {
+        if self.compatibility == CompatibilityPolicy::ImportOnly
+            && !Compatibility::check(&envelope.tls, Utc::now()).attachable()
+        {
+            return Ok(vec![]);
+        }
+        let annotations = &envelope.metadata.annotations;
+        let mut listeners_arns: BTreeSet<String> = BTreeSet::new();
+        if let Some(listeners) = annotations.get(LISTENERS_ANNOTATION) {
+            listeners_arns.extend(
+                parse_listeners(listeners)
+                    .into_iter()
+                    .filter(|listener_arn| self.owns(listener_arn)),
             );
-            std::env::set_var("AWS_ACCESS_KEY_ID", creds.access_key.clone());
-            std::env::set_var("AWS_SECRET_ACCESS_KEY", creds.secret_key.clone());
         }
-        let credentials_provider = ChainProvider::new();
+        if let Some(selector) = annotations.get(SELECTOR_ANNOTATION) {
+            let tags = parse_selector(selector)?;
+            listeners_arns.extend(self.retrieve_listeners_by_tags(&tags).await?);
+        }
+        if listeners_arns.is_empty() {
+            return Ok(self.load_balancers.clone());
+        }
+        self.known_listeners
+            .lock()
+            .unwrap()
+            .extend(listeners_arns.iter().cloned());
+        Ok(listeners_arns.into_iter().collect())
+    }
+
+    async fn attach(&self, published: &Published, target: &str) -> anyhow::Result<()> {
+        self.link_to_alb_listener(&published.reference, target)
+            .await
+    }
+
+    async fn detach(&self, published: &Published, target: &str) -> anyhow::Result<()> {
+        self.unlink_from_alb_listener(&published.reference, target)
+            .await
+    }
+
+    async fn remove(&self, published: &Published) -> anyhow::Result<()> {
+        let mut listeners_arns = published.targets.clone();
+        listeners_arns.extend(self.load_balancers.iter().cloned());
+        self.delete_certificate(&published.reference, &listeners_arns)
+            .await
+    }
+
+    fn accepts(&self, envelope: &Envelope) -> bool {
+        self.filters.matches(envelope)
+    }
+}
+
+impl AcmAlbTarget {
+    fn new(
+        config: AcmAlbTargetConfig,
+        identity_tag: String,
+        instance: String,
+        compatibility: CompatibilityPolicy,
+        throttle: Throttle,
+    ) -> anyhow::Result<Self> {
+        let name = config
+            .name
+            .clone()
+            .unwrap_or_else(|| match &config.account {
+                Some(account) => format!("{}/{}", account, config.region.name()),
+                None => String::from(config.region.name()),
+            });
+        if let Some(creds) = config.credentials.as_ref() {
+            debug!("Using credentials from config for {} : {:?}", name, creds);
+        }
+        let auth = match (config.credentials, config.auth) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "AWS credentials and auth of {} cannot be set together",
+                    name
+                ))
+            }
+            (Some(creds), None) => Some(AuthConfig::Static(creds)),
+            (None, auth) => auth,
+        };
+        let credentials_provider = CredentialsProvider::new(auth.as_ref(), &config.region)?;
         let acm_client = AcmClient::new_with(
-            Self::create_client()?,
+            create_client()?,
             credentials_provider.clone(),
             config.region.clone(),
         );
         let elb_client = ElbClient::new_with(
-            Self::create_client()?,
+            create_client()?,
             credentials_provider,
             config.region.clone(),
         );
-        let mut tag_managed_by = Tag::default();
-        tag_managed_by.key = String::from("ManagedBy");
-        tag_managed_by.value = Some(String::from("cert-sync"));
-        Ok(AcmAlbDestination {
-            config,
+        let tag_managed_by = Tag {
+            key: String::from("ManagedBy"),
+            value: Some(String::from("cert-sync")),
+        };
+        let scope_tags = vec![
+            Tag {
+                key: String::from(INSTANCE_TAG),
+                value: Some(instance),
+            },
+            Tag {
+                key: String::from(TARGET_TAG),
+                value: Some(name.clone()),
+            },
+        ];
+        Ok(AcmAlbTarget {
+            name,
+            region: config.region,
+            account: config.account,
+            load_balancers: config.load_balancers.unwrap_or_default(),
+            filters: config.filters.unwrap_or_default(),
             acm_client,
             elb_client,
+            throttle,
             tag_managed_by,
+            scope_tags,
+            identity_tag,
+            dry_run: false,
+            compatibility,
+            known_listeners: Mutex::new(BTreeSet::new()),
+            listeners_discovered: AtomicBool::new(false),
+            cert_tags: Mutex::new(TagCache::default()),
         })
     }
 
-    fn create_client() -> anyhow::Result<HttpClient<ProxyConnector<HttpsConnector<HttpConnector>>>>
-    {
-        let http_proxy = std::env::var("HTTP_PROXY")
-            .or_else(|_| std::env::var("http_proxy"))
-            .ok();
-        let https_proxy = std::env::var("HTTPS_PROXY")
-            .or_else(|_| std::env::var("https_proxy"))
-            .ok()
-            .or_else(|| http_proxy.clone());
-        let _no_proxy = std::env::var("NO_PROXY")
-            .or_else(|_| std::env::var("no_proxy"))
-            .ok();
-        let mut proxies: Vec<Proxy> = Vec::new();
-        if let Some(prox) = http_proxy {
-            proxies.push(Proxy::new(
-                Intercept::Http,
-                prox.parse::<Uri>().expect("Malformed HTTP_PROXY env var"),
-            ));
-        }
-        if let Some(prox) = https_proxy {
-            proxies.push(Proxy::new(
-                Intercept::Https,
-                prox.parse::<Uri>().expect("Malformed HTTPS_PROXY env var"),
-            ));
-        }
-        let https_connector = HttpsConnector::new();
-        let proxy_connector = match !proxies.is_empty() {
-            true => {
-                let mut proxy_connector =
-                    ProxyConnector::from_proxy(https_connector, proxies.pop().unwrap())?;
-                while !proxies.is_empty() {
-                    proxy_connector.add_proxy(proxies.pop().unwrap());
-                }
-                proxy_connector
+    /// Tells whether a resource, given its ARN, belongs to the account and
+    /// region of the target
+    fn owns(&self, arn: &str) -> bool {
+        let mut parts = arn.split(':').skip(3);
+        let region = parts.next();
+        let account = parts.next();
+        region == Some(self.region.name())
+            && match self.account {
+                Some(ref expected) => account == Some(expected.as_str()),
+                None => true,
             }
-            false => ProxyConnector::new(https_connector)?,
-        };
-        let mut hyper_builder = Client::builder();
-
-        // disabling due to connection closed issue
-        hyper_builder.pool_max_idle_per_host(0);
-        Ok(rusoto_core::HttpClient::from_builder(
-            hyper_builder,
-            proxy_connector,
-        ))
-    }
-
-    async fn send_to_acm(&self, tls: TLS) -> anyhow::Result<String> {
-        let domain = tls.domains.clone();
-        let existing_cert = self.retrieve_existing_cert(&tls).await?;
-        let new_cert = self.publish_certificate(tls, existing_cert).await?;
-        match new_cert.certificate_arn {
-            Some(arn) => Ok(arn),
-            None => Err(anyhow!(format!(
-                "Unable to create ACM certificate for cert with domains {}",
-                domain.iter().fold(String::new(), |acc, x| acc + x)
-            ))),
+    }
+
+    /// Applies the compatibility policy to a certificate ACM or ALB would
+    /// reject
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if the certificate must not be published
+    fn check_compatibility(&self, envelope: &Envelope) -> Result<(), PublishError> {
+        let compatibility = Compatibility::check(&envelope.tls, Utc::now());
+        if compatibility.attachable() {
+            return Ok(());
         }
+        match self.compatibility {
+            CompatibilityPolicy::Refuse => Err(PublishError::Rejected {
+                id: envelope.id(),
+                reason: compatibility.to_string(),
+            }),
+            CompatibilityPolicy::ImportOnly if !compatibility.importable() => {
+                Err(PublishError::Rejected {
+                    id: envelope.id(),
+                    reason: format!("not importable, {}", compatibility),
+                })
+            }
+            CompatibilityPolicy::ImportOnly => {
+                warn!(
+                    "Certificate {} will not be attached to listeners : {}",
+                    envelope.id(),
+                    compatibility
+                );
+                Ok(())
+            }
+            CompatibilityPolicy::Warn => {
+                warn!(
+                    "Certificate {} may be rejected by AWS : {}",
+                    envelope.id(),
+                    compatibility
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Prints the mutating call that would have been made in dry-run mode
+    fn print_plan(&self, operation: &str, details: serde_json::Value) {
+        println!("{}", plan_line(operation, details));
+    }
+
+    async fn send_to_acm(&self, envelope: Envelope) -> Result<String, PublishError> {
+        let existing_cert = self.retrieve_existing_cert(&envelope).await?;
+        self.publish_certificate(envelope, existing_cert).await
     }
 
+    /// Finds the ACM certificate related to the given source object
+    ///
+    /// A certificate whose identity tag holds the object identifier always wins.
+    /// Otherwise, the first certificate without identity tag covering exactly
+    /// the same domains is used, so that certificates imported before the
+    /// identity tag existed are still reused. Certificates of other cert-sync
+    /// deployments are never used.
     async fn retrieve_existing_cert(
         &self,
-        tls: &TLS,
-    ) -> anyhow::Result<Option<CertificateSummary>> {
-        let mut first_iter = true;
-        let mut next_token = Some(String::from(""));
-        while next_token.is_some() {
-            let mut request = ListCertificatesRequest::default();
-            if !first_iter && next_token.is_some() {
-                request.next_token = next_token
+        envelope: &Envelope,
+    ) -> anyhow::Result<Option<ExistingCert>> {
+        let id = envelope.id();
+        let tls = &envelope.tls;
+        let mut candidates: Vec<ExistingCert> = vec![];
+        for summary in self.list_certificates().await? {
+            let (arn, domain_name) = match summary {
+                CertificateSummary {
+                    certificate_arn: Some(arn),
+                    domain_name,
+                } => (arn, domain_name),
+                _ => continue,
+            };
+            let cert = ExistingCert {
+                tags: self.cached_tags(&arn).await?,
+                arn,
+            };
+            if cert.is_foreign(&self.scope_tags) {
+                continue;
             }
-            let certs_res = self.acm_client.list_certificates(request).await?;
-            if let Some(cert) = certs_res.certificate_summary_list.and_then(|certs| {
-                certs
-                    .into_iter()
-                    .filter(|cert| cert.domain_name.is_some())
-                    .find(|cert| tls.domains.contains(cert.domain_name.as_ref().unwrap()))
-            }) {
+            match cert.tag(&self.identity_tag) {
+                Some(identity) if identity == id => return Ok(Some(cert)),
+                // Belongs to another source object
+                Some(_) => continue,
+                None => {
+                    if matches!(domain_name, Some(ref domain) if tls.domains.contains(domain)) {
+                        candidates.push(cert);
+                    }
+                }
+            }
+        }
+        for cert in candidates {
+            let cert_domains = self.retrieve_domains(&cert.arn).await?;
+            if same_domains(&cert_domains, &tls.domains) {
                 return Ok(Some(cert));
             }
-            first_iter = false;
-            next_token = certs_res.next_token;
         }
         Ok(None)
     }
 
+    async fn list_certificates(&self) -> anyhow::Result<Vec<CertificateSummary>> {
+        let mut summaries: Vec<CertificateSummary> = vec![];
+        let mut next_token = None;
+        loop {
+            let request = ListCertificatesRequest {
+                includes: Some(Filters {
+                    key_types: Some(ACM_KEY_TYPES.iter().map(|key| String::from(*key)).collect()),
+                    ..Default::default()
+                }),
+                next_token,
+                ..Default::default()
+            };
+            let certs_res = self
+                .throttle
+                .call(
+                    || self.acm_client.list_certificates(request.clone()),
+                    is_retryable,
+                )
+                .await?;
+            summaries.extend(certs_res.certificate_summary_list.unwrap_or_default());
+            next_token = certs_res.next_token;
+            if next_token.is_none() {
+                return Ok(summaries);
+            }
+        }
+    }
+
+    /// Retrieves the tags of an ACM certificate, from the cache when known
+    async fn cached_tags(&self, cert_arn: &str) -> anyhow::Result<Vec<Tag>> {
+        if let Some(tags) = self.cert_tags.lock().unwrap().get(cert_arn) {
+            return Ok(tags);
+        }
+        let tags = self.list_tags(cert_arn).await?;
+        self.cert_tags
+            .lock()
+            .unwrap()
+            .insert(cert_arn, tags.clone());
+        Ok(tags)
+    }
+
+    async fn list_tags(&self, cert_arn: &str) -> anyhow::Result<Vec<Tag>> {
+        let request = ListTagsForCertificateRequest {
+            certificate_arn: String::from(cert_arn),
+        };
+        Ok(self
+            .throttle
+            .call(
+                || self.acm_client.list_tags_for_certificate(request.clone()),
+                is_retryable,
+            )
+            .await?
+            .tags
+            .unwrap_or_default())
+    }
+
+    /// Retrieves the Subject Alternative Names of an ACM certificate
+    async fn retrieve_domains(&self, cert_arn: &str) -> anyhow::Result<Vec<String>> {
+        let request = DescribeCertificateRequest {
+            certificate_arn: String::from(cert_arn),
+        };
+        Ok(self
+            .throttle
+            .call(
+                || self.acm_client.describe_certificate(request.clone()),
+                is_retryable,
+            )
+            .await?
+            .certificate
+            .and_then(|detail| detail.subject_alternative_names)
+            .unwrap_or_default())
+    }
+
+    /// Retrieves the ARNs of the AWS resources using an ACM certificate
+    async fn retrieve_users(&self, cert_arn: &str) -> anyhow::Result<Vec<String>> {
+        let request = DescribeCertificateRequest {
+            certificate_arn: String::from(cert_arn),
+        };
+        Ok(self
+            .throttle
+            .call(
+                || self.acm_client.describe_certificate(request.clone()),
+                is_retryable,
+            )
+            .await?
+            .certificate
+            .and_then(|detail| detail.in_use_by)
+            .unwrap_or_default())
+    }
+
+    /// Adds the HTTPS listeners of the load balancers using the certificates
+    /// to the known listeners. The listeners resolved from annotations before
+    /// a restart are then inspected too, so that the attachments to them are
+    /// reported and detached once stale
+    async fn discover_listeners(&self, certs: &[ExistingCert]) -> anyhow::Result<()> {
+        let mut load_balancers_arns: BTreeSet<String> = BTreeSet::new();
+        for cert in certs {
+            load_balancers_arns.extend(
+                self.retrieve_users(&cert.arn)
+                    .await?
+                    .into_iter()
+                    .filter(|arn| is_application_load_balancer(arn) && self.owns(arn)),
+            );
+        }
+        let mut listeners_arns: Vec<String> = vec![];
+        for load_balancer_arn in load_balancers_arns {
+            listeners_arns.extend(self.retrieve_https_listeners(load_balancer_arn).await?);
+        }
+        debug!(
+            "Listeners using the certificates of {} : {:?}",
+            self.name, listeners_arns
+        );
+        self.known_listeners.lock().unwrap().extend(listeners_arns);
+        Ok(())
+    }
+
     async fn publish_certificate(
         &self,
-        new_cert: TLS,
-        existing_cert: Option<CertificateSummary>,
-    ) -> anyhow::Result<ImportCertificateResponse> {
+        envelope: Envelope,
+        existing_cert: Option<ExistingCert>,
+    ) -> Result<String, PublishError> {
+        let id = envelope.id();
+        let tag_identity = Tag {
+            key: self.identity_tag.clone(),
+            value: Some(id.clone()),
+        };
+        let new_cert = envelope.tls;
+        let tag_fingerprint = Tag {
+            key: String::from(FINGERPRINT_TAG),
+            value: Some(new_cert.fingerprint().map(String::from).ok_or_else(|| {
+                PublishError::Rejected {
+                    id: id.clone(),
+                    reason: String::from("unable to fingerprint it"),
+                }
+            })?),
+        };
+
         // Create the request
-        let mut cert_req = ImportCertificateRequest::default();
-        cert_req.certificate = Bytes::from(new_cert.cert);
-        cert_req.private_key = Bytes::from(new_cert.key);
+        let mut cert_req = ImportCertificateRequest {
+            certificate: Bytes::from(new_cert.cert),
+            private_key: Bytes::from(new_cert.key),
+            ..Default::default()
+        };
         if !new_cert.chain.is_empty() {
             cert_req.certificate_chain = Some(Bytes::from(new_cert.chain.join("\n")));
         }
 
         let default_domain = String::from("");
-        let main_domain = new_cert.domains.get(0).unwrap_or(&default_domain);
-        let mut tag_name = Tag::default();
-        tag_name.key = String::from("Name");
-        tag_name.value = Some(main_domain.clone());
+        let main_domain = new_cert.domains.first().unwrap_or(&default_domain);
+        let tag_name = Tag {
+            key: String::from("Name"),
+            value: Some(main_domain.clone()),
+        };
 
-        let mut tag_domain = Tag::default();
-        tag_domain.key = String::from("Domain");
-        tag_domain.value = Some(main_domain.clone());
+        let tag_domain = Tag {
+            key: String::from("Domain"),
+            value: Some(main_domain.clone()),
+        };
+        let mut new_cert_tags = vec![
+            tag_name,
+            tag_domain,
+            tag_fingerprint.clone(),
+            self.tag_managed_by.clone(),
+            tag_identity.clone(),
+        ];
+        new_cert_tags.extend(self.scope_tags.iter().cloned());
 
+        // Tags cannot be set when re-importing, they are added afterwards
+        let mut missing_tags: Vec<Tag> = vec![];
         match existing_cert {
-            Some(cert_summary) => {
-                info!(
-                    "Use existing certificate ARN {}",
-                    cert_summary.certificate_arn.as_ref().unwrap()
+            Some(cert) => {
+                if cert.tag(&self.identity_tag).is_none() {
+                    missing_tags.push(tag_identity);
+                }
+                missing_tags.extend(
+                    self.scope_tags
+                        .iter()
+                        .filter(|scope| cert.tag(&scope.key).is_none())
+                        .cloned(),
                 );
-                cert_req.certificate_arn = cert_summary.certificate_arn;
+                if cert.tag(FINGERPRINT_TAG) == tag_fingerprint.value.as_deref() {
+                    info!("Certificate ARN {} is already up to date", cert.arn);
+                    self.add_tags(&cert.arn, missing_tags).await?;
+                    return Ok(cert.arn);
+                }
+                info!("Use existing certificate ARN {}", cert.arn);
+                missing_tags.push(tag_fingerprint);
+                cert_req.certificate_arn = Some(cert.arn);
             }
             None => {
-                info!(
-                    "Create new certificate for domain {}",
-                    tag_domain.value.as_ref().unwrap()
+                info!("Create new certificate for domain {}", main_domain);
+                cert_req.tags = Some(new_cert_tags.clone());
+            }
+        }
+
+        if self.dry_run {
+            let cert_arn = cert_req
+                .certificate_arn
+                .clone()
+                .unwrap_or_else(|| String::from(UNKNOWN_ARN));
+            self.print_plan(
+                "import_certificate",
+                json!({
+                    "certificate_arn": cert_req.certificate_arn,
+                    "domains": new_cert.domains,
+                    "tags": tags_to_json(cert_req.tags.as_deref().unwrap_or_default()),
+                }),
+            );
+            self.add_tags(&cert_arn, missing_tags).await?;
+            return Ok(cert_arn);
+        }
+
+        // Send the cert. A certificate deleted by hand since it was listed is
+        // imported again as a new one
+        let cert_res = match self.import_certificate(cert_req.clone()).await {
+            Err(RusotoError::Service(ImportCertificateError::ResourceNotFound(e)))
+                if cert_req.certificate_arn.is_some() =>
+            {
+                warn!(
+                    "Certificate ARN {} no longer exists, create a new one : {}",
+                    cert_req.certificate_arn.as_deref().unwrap_or_default(),
+                    e
                 );
-                cert_req.tags = Some(vec![tag_name, tag_domain, self.tag_managed_by.clone()]);
+                if let Some(ref cert_arn) = cert_req.certificate_arn {
+                    self.cert_tags.lock().unwrap().remove(cert_arn);
+                }
+                cert_req.certificate_arn = None;
+                cert_req.tags = Some(new_cert_tags);
+                missing_tags.clear();
+                self.import_certificate(cert_req.clone()).await
             }
+            res => res,
+        }
+        .map_err(|e| import_error(&id, e))?;
+        let cert_arn = cert_res.certificate_arn.ok_or_else(|| {
+            anyhow!(
+                "Unable to create ACM certificate for cert with domains {}",
+                main_domain
+            )
+        })?;
+        if let Some(tags) = cert_req.tags {
+            self.cert_tags.lock().unwrap().insert(&cert_arn, tags);
         }
+        self.add_tags(&cert_arn, missing_tags).await?;
+        Ok(cert_arn)
+    }
+
+    /// Imports a certificate into ACM. A new certificate may have been created
+    /// by a call failing on a server error, so only throttled calls are
+    /// retried then
+    async fn import_certificate(
+        &self,
+        request: ImportCertificateRequest,
+    ) -> Result<ImportCertificateResponse, RusotoError<ImportCertificateError>> {
+        let retryable = match request.certificate_arn {
+            Some(_) => is_retryable,
+            None => is_throttling,
+        };
+        self.throttle
+            .call(
+                || self.acm_client.import_certificate(request.clone()),
+                retryable,
+            )
+            .await
+    }
 
-        // Send the cert
-        let cert_res = self.acm_client.import_certificate(cert_req).await?;
-        Ok(cert_res)
+    /// Adds or overwrites tags of an ACM certificate
+    async fn add_tags(&self, cert_arn: &str, tags: Vec<Tag>) -> anyhow::Result<()> {
+        if tags.is_empty() {
+            return Ok(());
+        }
+        if self.dry_run {
+            self.print_plan(
+                "add_tags_to_certificate",
+                json!({ "certificate_arn": cert_arn, "tags": tags_to_json(&tags) }),
+            );
+            return Ok(());
+        }
+        let request = AddTagsToCertificateRequest {
+            certificate_arn: String::from(cert_arn),
+            tags,
+        };
+        self.throttle
+            .call(
+                || self.acm_client.add_tags_to_certificate(request.clone()),
+                is_retryable,
+            )
+            .await?;
+        self.cert_tags
+            .lock()
+            .unwrap()
+            .merge(cert_arn, &request.tags);
+        Ok(())
     }
 
+    /// Attaches the certificate to every given listener, even when some of
+    /// them fail
     async fn link_to_alb_listeners(
         &self,
-        cert_arn: String,
+        cert_arn: &str,
         listeners_arn: &[String],
-    ) -> anyhow::Result<()> {
-        let mut certificate = Certificate::default();
-        certificate.certificate_arn = Some(cert_arn);
-        let certificates = vec![certificate];
+    ) -> Result<(), PublishError> {
+        let mut errors: Vec<String> = vec![];
+        for listener_arn in listeners_arn {
+            if let Err(e) = self.link_to_alb_listener(cert_arn, listener_arn).await {
+                error!(
+                    "Unable to attach certificate {} to listener {} : {}",
+                    cert_arn, listener_arn, e
+                );
+                errors.push(format!("{} : {}", listener_arn, e));
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(PublishError::Unattached {
+                reference: String::from(cert_arn),
+                errors,
+            }),
+        }
+    }
+
+    async fn link_to_alb_listener(&self, cert_arn: &str, listener_arn: &str) -> anyhow::Result<()> {
+        let request = AddListenerCertificatesInput {
+            listener_arn: String::from(listener_arn),
+            certificates: vec![Certificate {
+                certificate_arn: Some(String::from(cert_arn)),
+                ..Default::default()
+            }],
+        };
+        if self.dry_run {
+            self.print_plan(
+                "add_listener_certificates",
+                json!({ "listener_arn": listener_arn, "certificate_arn": cert_arn }),
+            );
+            return Ok(());
+        }
+        self.throttle
+            .call(
+                || self.elb_client.add_listener_certificates(request.clone()),
+                is_retryable,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Detaches the certificate from every given listener. Failures are only
+    /// logged as the certificate may not be attached to all of them
+    async fn unlink_from_alb_listeners(&self, cert_arn: &str, listeners_arn: &[String]) {
         for listener_arn in listeners_arn {
-            let mut request = AddListenerCertificatesInput::default();
-            request.listener_arn = listener_arn.clone();
-            request.certificates = certificates.clone();
-            self.elb_client.add_listener_certificates(request).await?;
+            if let Err(e) = self.unlink_from_alb_listener(cert_arn, listener_arn).await {
+                warn!(
+                    "Unable to detach certificate {} from listener {} : {}",
+                    cert_arn, listener_arn, e
+                );
+            }
+        }
+    }
+
+    async fn unlink_from_alb_listener(
+        &self,
+        cert_arn: &str,
+        listener_arn: &str,
+    ) -> anyhow::Result<()> {
+        let request = RemoveListenerCertificatesInput {
+            listener_arn: String::from(listener_arn),
+            certificates: vec![Certificate {
+                certificate_arn: Some(String::from(cert_arn)),
+                ..Default::default()
+            }],
+        };
+        if self.dry_run {
+            self.print_plan(
+                "remove_listener_certificates",
+                json!({ "listener_arn": listener_arn, "certificate_arn": cert_arn }),
+            );
+            return Ok(());
+        }
+        self.throttle
+            .call(
+                || {
+                    self.elb_client
+                        .remove_listener_certificates(request.clone())
+                },
+                is_retryable,
+            )
+            .await?;
+        info!(
+            "Detached certificate {} from listener {}",
+            cert_arn, listener_arn
+        );
+        Ok(())
+    }
+
+    /// Lists the ARNs of the non default certificates of a listener
+    async fn retrieve_listener_certs(&self, listener_arn: &str) -> anyhow::Result<Vec<String>> {
+        let mut certs: Vec<String> = vec![];
+        let mut marker = None;
+        loop {
+            let request = DescribeListenerCertificatesInput {
+                listener_arn: String::from(listener_arn),
+                marker,
+                ..Default::default()
+            };
+            let res = self
+                .throttle
+                .call(
+                    || {
+                        self.elb_client
+                            .describe_listener_certificates(request.clone())
+                    },
+                    is_retryable,
+                )
+                .await?;
+            certs.extend(
+                res.certificates
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|cert| cert.is_default != Some(true))
+                    .filter_map(|cert| cert.certificate_arn),
+            );
+            marker = res.next_marker;
+            if marker.is_none() {
+                return Ok(certs);
+            }
+        }
+    }
+
+    /// Lists the HTTPS listeners of the load balancers carrying all the given
+    /// tags
+    async fn retrieve_listeners_by_tags(
+        &self,
+        tags: &[(String, String)],
+    ) -> anyhow::Result<Vec<String>> {
+        let mut load_balancers_arns: Vec<String> = vec![];
+        let mut marker = None;
+        loop {
+            let request = DescribeLoadBalancersInput {
+                marker,
+                ..Default::default()
+            };
+            let res = self
+                .throttle
+                .call(
+                    || self.elb_client.describe_load_balancers(request.clone()),
+                    is_retryable,
+                )
+                .await?;
+            load_balancers_arns.extend(
+                res.load_balancers
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|load_balancer| load_balancer.load_balancer_arn),
+            );
+            marker = res.next_marker;
+            if marker.is_none() {
+                break;
+            }
+        }
+        let mut listeners_arns: Vec<String> = vec![];
+        // DescribeTags accepts up to 20 resources per call
+        for chunk in load_balancers_arns.chunks(20) {
+            let request = DescribeTagsInput {
+                resource_arns: chunk.to_vec(),
+            };
+            let descriptions = self
+                .throttle
+                .call(
+                    || self.elb_client.describe_tags(request.clone()),
+                    is_retryable,
+                )
+                .await?
+                .tag_descriptions
+                .unwrap_or_default();
+            for description in descriptions {
+                let lb_tags = description.tags.unwrap_or_default();
+                let selected = tags.iter().all(|(key, value)| {
+                    lb_tags
+                        .iter()
+                        .any(|tag| &tag.key == key && tag.value.as_ref() == Some(value))
+                });
+                if let (true, Some(arn)) = (selected, description.resource_arn) {
+                    listeners_arns.extend(self.retrieve_https_listeners(arn).await?);
+                }
+            }
+        }
+        Ok(listeners_arns)
+    }
+
+    async fn retrieve_https_listeners(
+        &self,
+        load_balancer_arn: String,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut listeners_arns: Vec<String> = vec![];
+        let mut marker = None;
+        loop {
+            let request = DescribeListenersInput {
+                load_balancer_arn: Some(load_balancer_arn.clone()),
+                marker,
+                ..Default::default()
+            };
+            let res = self
+                .throttle
+                .call(
+                    || self.elb_client.describe_listeners(request.clone()),
+                    is_retryable,
+                )
+                .await?;
+            listeners_arns.extend(
+                res.listeners
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|listener| listener.protocol.as_deref() == Some("HTTPS"))
+                    .filter_map(|listener| listener.listener_arn),
+            );
+            marker = res.next_marker;
+            if marker.is_none() {
+                return Ok(listeners_arns);
+            }
+        }
+    }
+
+    /// Detaches the certificate from the given listeners, then deletes it
+    /// from ACM
+    async fn delete_certificate(
+        &self,
+        cert_arn: &str,
+        listeners_arns: &[String],
+    ) -> anyhow::Result<()> {
+        self.unlink_from_alb_listeners(cert_arn, listeners_arns)
+            .await;
+        info!("Delete certificate ARN {}", cert_arn);
+        if self.dry_run {
+            self.print_plan("delete_certificate", json!({ "certificate_arn": cert_arn }));
+            return Ok(());
         }
+        let request = DeleteCertificateRequest {
+            certificate_arn: String::from(cert_arn),
+        };
+        self.throttle
+            .call(
+                || self.acm_client.delete_certificate(request.clone()),
+                is_retryable,
+            )
+            .await?;
+        self.cert_tags.lock().unwrap().remove(cert_arn);
         Ok(())
     }
 }
 
+pub(super) fn create_client(
+) -> anyhow::Result<HttpClient<ProxyConnector<HttpsConnector<HttpConnector>>>> {
+    let http_proxy = std::env::var("HTTP_PROXY")
+        .or_else(|_| std::env::var("http_proxy"))
+        .ok();
+    let https_proxy = std::env::var("HTTPS_PROXY")
+        .or_else(|_| std::env::var("https_proxy"))
+        .ok()
+        .or_else(|| http_proxy.clone());
+    let _no_proxy = std::env::var("NO_PROXY")
+        .or_else(|_| std::env::var("no_proxy"))
+        .ok();
+    let mut proxies: Vec<Proxy> = Vec::new();
+    if let Some(prox) = http_proxy {
+        proxies.push(Proxy::new(
+            Intercept::Http,
+            prox.parse::<Uri>().expect("Malformed HTTP_PROXY env var"),
+        ));
+    }
+    if let Some(prox) = https_proxy {
+        proxies.push(Proxy::new(
+            Intercept::Https,
+            prox.parse::<Uri>().expect("Malformed HTTPS_PROXY env var"),
+        ));
+    }
+    let https_connector = HttpsConnector::new();
+    let proxy_connector = match !proxies.is_empty() {
+        true => {
+            let mut proxy_connector =
+                ProxyConnector::from_proxy(https_connector, proxies.pop().unwrap())?;
+            while let Some(proxy) = proxies.pop() {
+                proxy_connector.add_proxy(proxy);
+            }
+            proxy_connector
+        }
+        false => ProxyConnector::new(https_connector)?,
+    };
+    let mut hyper_builder = Client::builder();
+
+    // disabling due to connection closed issue
+    hyper_builder.pool_max_idle_per_host(0);
+    Ok(rusoto_core::HttpClient::from_builder(
+        hyper_builder,
+        proxy_connector,
+    ))
+}
+
+/// Error codes of AWS APIs rejecting calls over their rate limit
+const THROTTLING_CODES: [&str; 5] = [
+    "Throttling",
+    "ThrottlingException",
+    "TooManyRequestsException",
+    "RequestLimitExceeded",
+    "RequestThrottled",
+];
+
+/// Tells whether an AWS call failed because of its rate limit. Such errors
+/// are not modeled by Rusoto, so the response itself is inspected
+fn is_throttling<E>(error: &RusotoError<E>) -> bool {
+    match error {
+        RusotoError::Unknown(response) => {
+            let body = response.body_as_str();
+            response.status.as_u16() == 429
+                || THROTTLING_CODES.iter().any(|code| {
+                    body.contains(&format!(">{}<", code)) || body.contains(&format!("\"{}\"", code))
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Tells apart the certificates ACM refuses, which cannot be imported as is,
+/// from the failures of the import call itself. Quotas and tags can be fixed
+/// without changing the certificate, so they are not rejections
+fn import_error(id: &str, error: RusotoError<ImportCertificateError>) -> PublishError {
+    let rejected = match &error {
+        RusotoError::Service(ImportCertificateError::InvalidParameter(_))
+        | RusotoError::Validation(_) => true,
+        RusotoError::Unknown(response) => response.body_as_str().contains("ValidationException"),
+        _ => false,
+    };
+    match rejected {
+        true => PublishError::Rejected {
+            id: String::from(id),
+            reason: format!("ACM refuses it, {}", error),
+        },
+        false => PublishError::Failed(error.into()),
+    }
+}
+
+/// Tells whether an AWS call may succeed when made again : it was throttled,
+/// it failed on the server side, or it did not reach AWS
+fn is_retryable<E>(error: &RusotoError<E>) -> bool {
+    match error {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(response) => response.status.is_server_error() || is_throttling(error),
+        _ => false,
+    }
+}
+
+/// Tells whether an ARN is the one of an Application Load Balancer, among the
+/// resources an ACM certificate may be used by
+fn is_application_load_balancer(arn: &str) -> bool {
+    arn.starts_with("arn:aws:elasticloadbalancing:") && arn.contains(":loadbalancer/app/")
+}
+
+/// Parses a comma separated list of listeners ARNs
+fn parse_listeners(listeners: &str) -> Vec<String> {
+    listeners
+        .split(',')
+        .map(str::trim)
+        .filter(|listener| !listener.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Parses a load balancer tags selector formatted as `key=value,key=value`
+fn parse_selector(selector: &str) -> anyhow::Result<Vec<(String, String)>> {
+    selector
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => Ok((String::from(key.trim()), String::from(value.trim()))),
+            None => Err(anyhow!("Invalid load balancer selector {}", selector)),
+        })
+        .collect()
+}
+
+/// Builds the JSON line describing a call skipped in dry-run mode
+fn plan_line(operation: &str, mut details: serde_json::Value) -> serde_json::Value {
+    details["dry_run"] = json!(true);
+    details["operation"] = json!(operation);
+    details
+}
+
+/// Converts ACM tags into a JSON object
+fn tags_to_json(tags: &[Tag]) -> serde_json::Value {
+    tags.iter()
+        .map(|tag| (tag.key.clone(), json!(tag.value)))
+        .collect::<serde_json::Map<String, serde_json::Value>>()
+        .into()
+}
+
+/// Matches a domain against a domain or a wildcard pattern such as
+/// `*.example.org`, which covers a single level of subdomains
+fn domain_matches(pattern: &str, domain: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some(suffix) if suffix.starts_with('.') => matches!(
+            domain.strip_suffix(suffix),
+            Some(label) if !label.is_empty() && !label.contains('.')
+        ),
+        _ => pattern == domain,
+    }
+}
+
+/// Reads a region either from its name or in the Rusoto form, a list holding
+/// its name and optionally its endpoint
+fn deserialize_region<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Region, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RegionConfig {
+        Name(String),
+        Region(Region),
+    }
+    match RegionConfig::deserialize(deserializer)? {
+        RegionConfig::Name(name) => Region::from_str(&name).map_err(serde::de::Error::custom),
+        RegionConfig::Region(region) => Ok(region),
+    }
+}
+
+/// Returns the configured targets, or the single target described by the
+/// settings of the destination itself
+fn target_configs(config: &AcmAlbConfig) -> anyhow::Result<Vec<AcmAlbTargetConfig>> {
+    match (&config.targets, &config.region) {
+        (Some(_), Some(_)) => Err(anyhow!("AWS region and targets cannot be set together")),
+        (Some(targets), None) if targets.is_empty() => Err(anyhow!("No AWS target is configured")),
+        (Some(targets), None) => Ok(targets.clone()),
+        (None, Some(region)) => Ok(vec![AcmAlbTargetConfig {
+            name: None,
+            region: region.clone(),
+            account: None,
+            credentials: config.credentials.clone(),
+            auth: config.auth.clone(),
+            load_balancers: config.load_balancers.clone(),
+            filters: None,
+        }]),
+        (None, None) => Err(anyhow!("Either an AWS region or targets must be set")),
+    }
+}
+
+/// Compares two lists of domains regardless of their order
+fn same_domains(left: &[String], right: &[String]) -> bool {
+    left.iter().collect::<HashSet<_>>() == right.iter().collect::<HashSet<_>>()
+}
+
 /// Get config from file
 fn parse_config(config_str: &str) -> anyhow::Result<AcmAlbConfig> {
     let config_from_file: AwsRootConfig = serde_yaml::from_str(config_str)?;
     let config = config_from_file.aws;
 
     debug!("Config : {:?}", config);
+    if let Some(rate_limit) = &config.rate_limit {
+        rate_limit.validate()?;
+    }
     // Set region from env if it exists
     // if let Some(env_region) = option_env!("AWS_REGION") {
     //     if let Ok(region) = Region::from_str(env_region) {
@@ -285,9 +1545,21 @@ fn parse_config(config_str: &str) -> anyhow::Result<AcmAlbConfig> {
 
 #[cfg(test)]
 mod tests {
-    use super::parse_config;
+    use super::{
+        domain_matches, import_error, is_application_load_balancer, is_retryable, is_throttling,
+        parse_config, parse_listeners, parse_selector, plan_line, same_domains, tags_to_json,
+        target_configs, AcmAlbDestination, AcmAlbTarget, AuthConfig, CompatibilityPolicy,
+        ExistingCert, SystemClock, TagCache, Throttle,
+    };
+    use crate::common::{Envelope, Metadata, ObjectRef, Redacted, TLS};
+    use crate::destination::Published;
+    use hyper::StatusCode;
     use indoc::indoc;
-    use rusoto_core::Region;
+    use rusoto_acm::{ImportCertificateError, Tag};
+    use rusoto_core::request::{BufferedHttpResponse, HttpDispatchError};
+    use rusoto_core::{Region, RusotoError};
+    use serde_json::json;
+    use std::sync::Arc;
 
     #[test]
     fn parse_config_full_test() -> anyhow::Result<()> {
@@ -302,15 +1574,36 @@ mod tests {
               load_balancers:
                 - a
                 - b
+              identity_tag: Owner
+              instance: staging
+              dry_run: true
+              compatibility: import_only
+              rate_limit:
+                rate: 2
+                burst: 4
+              retry:
+                max_attempts: 3
             "
         ));
         let config = parse_config(&config_str)?;
-        assert_eq!(config.region, Region::EuWest3);
+        assert_eq!(config.region, Some(Region::EuWest3));
         let credentials = config.credentials.unwrap();
-        assert_eq!(credentials.access_key, "access_key");
-        assert_eq!(credentials.secret_key, "secret_key");
+        assert_eq!(
+            credentials.access_key,
+            Some(Redacted::from(String::from("access_key")))
+        );
+        assert_eq!(
+            credentials.secret_key,
+            Some(Redacted::from(String::from("secret_key")))
+        );
         let load_balancers = config.load_balancers.unwrap();
         assert_eq!(load_balancers, vec!["a", "b"]);
+        assert_eq!(config.identity_tag, Some(String::from("Owner")));
+        assert_eq!(config.instance, Some(String::from("staging")));
+        assert_eq!(config.dry_run, Some(true));
+        assert_eq!(config.compatibility, Some(CompatibilityPolicy::ImportOnly));
+        assert!(config.rate_limit.is_some());
+        assert!(config.retry.is_some());
         Ok(())
     }
 
@@ -324,9 +1617,406 @@ mod tests {
             "
         ));
         let config = parse_config(&config_str)?;
-        assert_eq!(config.region, Region::EuWest3);
+        assert_eq!(config.region, Some(Region::EuWest3));
         assert_eq!(config.credentials, None);
+        assert_eq!(config.rate_limit, None);
+        assert_eq!(config.retry, None);
         assert_eq!(config.load_balancers, None);
+        assert_eq!(config.identity_tag, None);
+        assert_eq!(config.dry_run, None);
         Ok(())
     }
+
+    #[test]
+    fn parse_config_targets_test() -> anyhow::Result<()> {
+        let config_str = String::from(indoc!(
+            "
+            aws:
+              identity_tag: Owner
+              targets:
+                - name: alb
+                  region: eu-west-3
+                  account: \"123456789012\"
+                  load_balancers:
+                    - a
+                - region:
+                    - us-east-1
+                  auth:
+                    assume_role:
+                      role_arn: arn:aws:iam::210987654321:role/cert-sync
+                  filters:
+                    domains:
+                      - \"*.example.org\"
+                    annotations:
+                      cert-sync.io/cloudfront: \"true\"
+            "
+        ));
+        let config = parse_config(&config_str)?;
+        let targets = target_configs(&config)?;
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].name.as_deref(), Some("alb"));
+        assert_eq!(targets[0].region, Region::EuWest3);
+        assert_eq!(targets[0].account.as_deref(), Some("123456789012"));
+        assert_eq!(targets[1].region, Region::UsEast1);
+        assert!(matches!(
+            &targets[1].auth,
+            Some(AuthConfig::AssumeRole(assume_role))
+                if assume_role.role_arn == "arn:aws:iam::210987654321:role/cert-sync"
+        ));
+        let filters = targets[1].filters.clone().unwrap();
+        assert_eq!(filters.domains.unwrap(), vec!["*.example.org"]);
+        assert_eq!(
+            filters.annotations.unwrap()["cert-sync.io/cloudfront"],
+            "true"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn target_configs_test() -> anyhow::Result<()> {
+        let single = parse_config("aws: { region: [eu-west-3], load_balancers: [a] }")?;
+        let targets = target_configs(&single)?;
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].region, Region::EuWest3);
+        assert_eq!(targets[0].load_balancers, Some(vec![String::from("a")]));
+        let both = parse_config("aws: { region: [eu-west-3], targets: [{ region: us-east-1 }] }")?;
+        assert!(target_configs(&both).is_err());
+        assert!(target_configs(&parse_config("aws: {}")?).is_err());
+        assert!(target_configs(&parse_config("aws: { targets: [] }")?).is_err());
+        assert!(parse_config("aws: { region: [eu-west-3], rate_limit: { rate: 0 } }").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn target_filters_test() -> anyhow::Result<()> {
+        let config_str = indoc!(
+            "
+            aws:
+              targets:
+                - region: us-east-1
+                  account: \"123456789012\"
+                  filters:
+                    domains:
+                      - \"*.example.org\"
+                    annotations:
+                      cert-sync.io/cloudfront: \"true\"
+            "
+        );
+        let config = target_configs(&parse_config(config_str)?)?.remove(0);
+        let throttle = Throttle::new(None, None, Arc::new(SystemClock));
+        let target = AcmAlbTarget::new(
+            config,
+            String::from("Source"),
+            String::from("default"),
+            CompatibilityPolicy::Warn,
+            throttle,
+        )?;
+        assert_eq!(target.name, "123456789012/us-east-1");
+
+        let object = ObjectRef {
+            namespace: Some(String::from("default")),
+            name: String::from("web"),
+            uid: None,
+        };
+        let mut metadata = Metadata::new(String::from("test"), object);
+        metadata.annotations.insert(
+            String::from("cert-sync.io/cloudfront"),
+            String::from("true"),
+        );
+        let tls = TLS {
+            domains: vec![String::from("www.example.org")],
+            ..Default::default()
+        };
+        let mut envelope = Envelope::new(tls, metadata);
+        assert!(target.filters.matches(&envelope));
+        envelope.tls.domains = vec![String::from("example.com")];
+        assert!(!target.filters.matches(&envelope));
+        envelope.tls.domains = vec![String::from("www.example.org")];
+        envelope.metadata.annotations.clear();
+        assert!(!target.filters.matches(&envelope));
+
+        assert!(
+            target.owns("arn:aws:elasticloadbalancing:us-east-1:123456789012:listener/app/web/1/2")
+        );
+        assert!(!target
+            .owns("arn:aws:elasticloadbalancing:eu-west-3:123456789012:listener/app/web/1/2"));
+        assert!(!target.owns("arn:aws:acm:us-east-1:210987654321:certificate/1"));
+        Ok(())
+    }
+
+    #[test]
+    fn domain_matches_test() {
+        assert!(domain_matches("example.org", "example.org"));
+        assert!(domain_matches("*.example.org", "www.example.org"));
+        assert!(domain_matches("*.example.org", "*.example.org"));
+        assert!(!domain_matches("*.example.org", "example.org"));
+        assert!(!domain_matches("*.example.org", "a.www.example.org"));
+        assert!(!domain_matches("example.org", "www.example.org"));
+    }
+
+    #[test]
+    fn is_application_load_balancer_test() {
+        assert!(is_application_load_balancer(
+            "arn:aws:elasticloadbalancing:eu-west-3:123456789012:loadbalancer/app/web/50dc6c495c0c9188"
+        ));
+        assert!(!is_application_load_balancer(
+            "arn:aws:elasticloadbalancing:eu-west-3:123456789012:loadbalancer/net/tcp/50dc6c495c0c9188"
+        ));
+        assert!(!is_application_load_balancer(
+            "arn:aws:cloudfront::123456789012:distribution/EDFDVBD6EXAMPLE"
+        ));
+    }
+
+    #[test]
+    fn parse_listeners_test() {
+        assert_eq!(parse_listeners("arn1, arn2,,"), vec!["arn1", "arn2"]);
+        assert!(parse_listeners("").is_empty());
+    }
+
+    #[test]
+    fn parse_selector_test() -> anyhow::Result<()> {
+        assert_eq!(
+            parse_selector("env=prod, team = web")?,
+            vec![
+                (String::from("env"), String::from("prod")),
+                (String::from("team"), String::from("web")),
+            ]
+        );
+        assert!(parse_selector("env").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn plan_line_test() {
+        let tags = vec![Tag {
+            key: String::from("ManagedBy"),
+            value: Some(String::from("cert-sync")),
+        }];
+        let line = plan_line(
+            "add_tags_to_certificate",
+            json!({ "certificate_arn": "arn", "tags": tags_to_json(&tags) }),
+        );
+        assert_eq!(
+            line,
+            json!({
+                "certificate_arn": "arn",
+                "dry_run": true,
+                "operation": "add_tags_to_certificate",
+                "tags": { "ManagedBy": "cert-sync" },
+            })
+        );
+    }
+
+    #[test]
+    fn holder_test() -> anyhow::Result<()> {
+        let config_str = indoc!(
+            "
+            aws:
+              targets:
+                - name: blue
+                  region: eu-west-3
+                - name: green
+                  region: eu-west-3
+            "
+        );
+        let destination = AcmAlbDestination::new(config_str)?;
+        let mut published = Published {
+            id: String::from("default/web"),
+            reference: String::from("arn:aws:acm:eu-west-3:123456789012:certificate/1"),
+            fingerprint: None,
+            targets: vec![],
+            holder: Some(String::from("green")),
+        };
+        assert_eq!(destination.holder(&published)?.name, "green");
+        // Both targets match the ARN
+        published.holder = None;
+        assert!(destination.holder(&published).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn shared_region_test() -> anyhow::Result<()> {
+        let config_str = indoc!(
+            "
+            aws:
+              instance: production
+              targets:
+                - name: blue
+                  region: eu-west-3
+                  filters:
+                    domains: [blue.example.org]
+                - name: green
+                  region: eu-west-3
+                  filters:
+                    domains: [green.example.org]
+            "
+        );
+        let destination = AcmAlbDestination::new(config_str)?;
+        let (blue, green) = (&destination.targets[0], &destination.targets[1]);
+        let mut tags = vec![blue.tag_managed_by.clone()];
+        tags.extend(blue.scope_tags.iter().cloned());
+        let cert = ExistingCert {
+            arn: String::from("arn:aws:acm:eu-west-3:123456789012:certificate/1"),
+            tags,
+        };
+        // Each target only lists and reuses its own certificates
+        assert!(cert.is_owned(&blue.tag_managed_by, &blue.scope_tags));
+        assert!(!cert.is_foreign(&blue.scope_tags));
+        assert!(!cert.is_owned(&green.tag_managed_by, &green.scope_tags));
+        assert!(cert.is_foreign(&green.scope_tags));
+        Ok(())
+    }
+
+    #[test]
+    fn ownership_test() {
+        let tag = |key: &str, value: &str| Tag {
+            key: String::from(key),
+            value: Some(String::from(value)),
+        };
+        let managed_by = tag("ManagedBy", "cert-sync");
+        let scope = vec![tag("Instance", "production"), tag("Target", "blue")];
+        let cert = |tags: Vec<Tag>| ExistingCert {
+            arn: String::from("arn:aws:acm:eu-west-3:123456789012:certificate/1"),
+            tags,
+        };
+        let own = cert(vec![managed_by.clone(), scope[0].clone(), scope[1].clone()]);
+        assert!(own.is_owned(&managed_by, &scope));
+        assert!(!own.is_foreign(&scope));
+        // Reused for a source object through its domains only, not deleted
+        assert!(!own.is_deletable(&managed_by, &scope, "Source", "default/web"));
+        let mut identified = own.tags.clone();
+        identified.push(tag("Source", "default/web"));
+        let identified = cert(identified);
+        assert!(identified.is_deletable(&managed_by, &scope, "Source", "default/web"));
+        assert!(!identified.is_deletable(&managed_by, &scope, "Source", "default/api"));
+        // Imported by another deployment, neither reused nor deleted
+        let foreign = cert(vec![
+            managed_by.clone(),
+            tag("Instance", "staging"),
+            scope[1].clone(),
+        ]);
+        assert!(!foreign.is_owned(&managed_by, &scope));
+        assert!(foreign.is_foreign(&scope));
+        // Imported by another target of the same account and region
+        let sibling = cert(vec![
+            managed_by.clone(),
+            scope[0].clone(),
+            tag("Target", "green"),
+        ]);
+        assert!(!sibling.is_owned(&managed_by, &scope));
+        assert!(sibling.is_foreign(&scope));
+        // Imported before the instance and target tags existed, reused but
+        // not deleted
+        let legacy = cert(vec![managed_by.clone()]);
+        assert!(!legacy.is_owned(&managed_by, &scope));
+        assert!(!legacy.is_foreign(&scope));
+    }
+
+    #[test]
+    fn tag_cache_test() {
+        let tag = |key: &str, value: &str| Tag {
+            key: String::from(key),
+            value: Some(String::from(value)),
+        };
+        let arn = "arn:aws:acm:eu-west-3:123456789012:certificate/1";
+        let mut cache = TagCache::default();
+        cache.insert(
+            arn,
+            vec![tag("Source", "default/web"), tag("Fingerprint", "a")],
+        );
+        cache.merge(
+            arn,
+            &[tag("Fingerprint", "b"), tag("Instance", "production")],
+        );
+        assert_eq!(
+            Some(vec![
+                tag("Source", "default/web"),
+                tag("Fingerprint", "b"),
+                tag("Instance", "production")
+            ]),
+            cache.get(arn)
+        );
+        // Unknown certificates are left to be listed
+        cache.merge("other", &[tag("Fingerprint", "b")]);
+        assert_eq!(None, cache.get("other"));
+        cache.remove(arn);
+        assert_eq!(None, cache.get(arn));
+    }
+
+    #[test]
+    fn same_domains_test() {
+        let apex = vec![String::from("example.org")];
+        let wildcard = vec![String::from("*.example.org")];
+        let both = vec![String::from("example.org"), String::from("*.example.org")];
+        let both_reversed = vec![String::from("*.example.org"), String::from("example.org")];
+        assert!(same_domains(&both, &both_reversed));
+        assert!(!same_domains(&apex, &wildcard));
+        assert!(!same_domains(&apex, &both));
+    }
+
+    #[test]
+    fn is_retryable_test() {
+        let response = |status: u16, body: &str| -> RusotoError<ImportCertificateError> {
+            RusotoError::Unknown(BufferedHttpResponse {
+                status: StatusCode::from_u16(status).unwrap(),
+                body: bytes::Bytes::from(String::from(body)),
+                headers: Default::default(),
+            })
+        };
+        let throttled = response(
+            400,
+            "<ErrorResponse><Error><Code>Throttling</Code></Error></ErrorResponse>",
+        );
+        assert!(is_throttling(&throttled));
+        assert!(is_retryable(&throttled));
+        let throttled = response(400, r#"{"__type":"ThrottlingException"}"#);
+        assert!(is_throttling(&throttled));
+        assert!(is_throttling(&response(429, "")));
+
+        let server_error = response(503, "");
+        assert!(!is_throttling(&server_error));
+        assert!(is_retryable(&server_error));
+        let dispatch = RusotoError::<ImportCertificateError>::HttpDispatch(HttpDispatchError::new(
+            String::from("connection reset"),
+        ));
+        assert!(is_retryable(&dispatch));
+
+        assert!(!is_retryable(&response(
+            400,
+            r#"{"__type":"AccessDeniedException"}"#
+        )));
+        assert!(!is_retryable(&RusotoError::Service(
+            ImportCertificateError::LimitExceeded(String::from("quota"))
+        )));
+    }
+
+    #[test]
+    fn import_error_test() {
+        let rejected = import_error(
+            "default/a",
+            RusotoError::Service(ImportCertificateError::InvalidParameter(String::from(
+                "bad chain",
+            ))),
+        );
+        assert!(rejected.is_permanent());
+        let invalid = import_error(
+            "default/a",
+            RusotoError::Unknown(BufferedHttpResponse {
+                status: StatusCode::BAD_REQUEST,
+                body: bytes::Bytes::from(r#"{"__type":"ValidationException"}"#),
+                headers: Default::default(),
+            }),
+        );
+        assert!(invalid.is_permanent());
+        let dispatch = import_error(
+            "default/a",
+            RusotoError::HttpDispatch(HttpDispatchError::new(String::from("timeout"))),
+        );
+        assert!(!dispatch.is_permanent());
+        let quota = import_error(
+            "default/a",
+            RusotoError::Service(ImportCertificateError::LimitExceeded(String::from("quota"))),
+        );
+        assert!(!quota.is_permanent());
+    }
 }
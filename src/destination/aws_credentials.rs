@@ -0,0 +1,534 @@
+use super::aws::create_client;
+use crate::common::{read_secret, Redacted};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use k8s_openapi::api::core::v1::Secret;
+use kube::{api::Api, Client};
+use rusoto_core::Region;
+use rusoto_credential::{
+    AwsCredentials, ChainProvider, CredentialsError, ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_sts::{
+    AssumeRoleWithWebIdentityRequest, NewAwsCredsForStsCreds, Sts,
+    StsAssumeRoleSessionCredentialsProvider, StsClient,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Session name of the assumed roles, unless overridden with `session_name`
+const DEFAULT_SESSION_NAME: &str = "cert-sync";
+
+/// Minutes before expiry at which temporary credentials are requested again
+const REFRESH_MARGIN: i64 = 5;
+
+/// Minutes after which the keys of a Kubernetes Secret are read again, so
+/// that rotated keys are picked up
+const SECRET_REFRESH: i64 = 10;
+
+/// Namespace of cert-sync, where Secrets are read unless `namespace` is set
+const NAMESPACE_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+/// Environment variables set by EKS for IAM roles for service accounts
+const ROLE_ARN_ENV: &str = "AWS_ROLE_ARN";
+const TOKEN_FILE_ENV: &str = "AWS_WEB_IDENTITY_TOKEN_FILE";
+
+/// Access keys, each given inline, read from a file or from an environment
+/// variable, or both read from a Kubernetes Secret
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(super) struct AcmAlbCredentials {
+    pub(super) access_key: Option<Redacted>,
+    pub(super) access_key_file: Option<String>,
+    pub(super) access_key_env: Option<String>,
+    pub(super) secret_key: Option<Redacted>,
+    pub(super) secret_key_file: Option<String>,
+    pub(super) secret_key_env: Option<String>,
+    pub(super) secret: Option<SecretRef>,
+}
+
+/// A Kubernetes Secret holding access keys
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct SecretRef {
+    pub(super) name: String,
+    /// Defaults to the namespace of cert-sync
+    pub(super) namespace: Option<String>,
+    /// Key of the access key, defaults to `access_key`
+    pub(super) access_key: Option<String>,
+    /// Key of the secret key, defaults to `secret_key`
+    pub(super) secret_key: Option<String>,
+}
+
+impl AcmAlbCredentials {
+    /// Tells whether any key is given other than through a Kubernetes Secret
+    fn has_keys(&self) -> bool {
+        self.access_key.is_some()
+            || self.access_key_file.is_some()
+            || self.access_key_env.is_some()
+            || self.secret_key.is_some()
+            || self.secret_key_file.is_some()
+            || self.secret_key_env.is_some()
+    }
+
+    /// Reads the keys given inline, from files or from environment variables
+    fn read(&self) -> anyhow::Result<(Redacted, Redacted)> {
+        let access_key = read_secret(
+            "access_key",
+            self.access_key.as_ref(),
+            self.access_key_file.as_deref(),
+            self.access_key_env.as_deref(),
+        )?;
+        let secret_key = read_secret(
+            "secret_key",
+            self.secret_key.as_ref(),
+            self.secret_key_file.as_deref(),
+            self.secret_key_env.as_deref(),
+        )?;
+        Ok((access_key, secret_key))
+    }
+}
+
+/// How to obtain AWS credentials. Without it, the web identity of EKS IAM
+/// roles for service accounts is used when available, then the environment,
+/// the profile files and the instance or container role
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum AuthConfig {
+    Static(AcmAlbCredentials),
+    AssumeRole(AssumeRoleConfig),
+    WebIdentity(WebIdentityConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct AssumeRoleConfig {
+    pub(super) role_arn: String,
+    pub(super) external_id: Option<String>,
+    pub(super) session_name: Option<String>,
+    /// Seconds the assumed credentials are valid, defaults to one hour
+    pub(super) duration: Option<u64>,
+    /// Credentials used to assume the role, the default ones if not set
+    pub(super) source: Option<Box<AuthConfig>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(super) struct WebIdentityConfig {
+    /// Defaults to `AWS_ROLE_ARN`
+    pub(super) role_arn: Option<String>,
+    /// Defaults to `AWS_WEB_IDENTITY_TOKEN_FILE`
+    pub(super) token_file: Option<String>,
+    pub(super) session_name: Option<String>,
+}
+
+impl WebIdentityConfig {
+    /// Reads the settings left unset from the environment
+    fn resolve(&self) -> anyhow::Result<(String, String)> {
+        let role_arn = match &self.role_arn {
+            Some(role_arn) => role_arn.clone(),
+            None => std::env::var(ROLE_ARN_ENV)
+                .map_err(|_| anyhow!("Web identity role_arn or {} required", ROLE_ARN_ENV))?,
+        };
+        let token_file = match &self.token_file {
+            Some(token_file) => token_file.clone(),
+            None => std::env::var(TOKEN_FILE_ENV)
+                .map_err(|_| anyhow!("Web identity token_file or {} required", TOKEN_FILE_ENV))?,
+        };
+        Ok((role_arn, token_file))
+    }
+}
+
+/// Provides the credentials of any authentication mode, so that AWS clients
+/// have a single type whatever the configuration
+#[derive(Clone)]
+pub(super) struct CredentialsProvider(Arc<dyn ProvideAwsCredentials + Send + Sync>);
+
+#[async_trait]
+impl ProvideAwsCredentials for CredentialsProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        self.0.credentials().await
+    }
+}
+
+impl CredentialsProvider {
+    /// Builds the provider of an authentication mode. Temporary credentials
+    /// are cached and requested again shortly before they expire
+    ///
+    /// STS is called in the given region, so that a target does not depend on
+    /// the availability of another one.
+    pub(super) fn new(auth: Option<&AuthConfig>, region: &Region) -> anyhow::Result<Self> {
+        let provider: Arc<dyn ProvideAwsCredentials + Send + Sync> = match auth {
+            None if std::env::var(TOKEN_FILE_ENV).is_ok() => {
+                return Self::new(
+                    Some(&AuthConfig::WebIdentity(WebIdentityConfig::default())),
+                    region,
+                );
+            }
+            None => Arc::new(Cached::new(ChainProvider::new())),
+            Some(AuthConfig::Static(credentials)) => match &credentials.secret {
+                None => {
+                    let (access_key, secret_key) = credentials.read()?;
+                    Arc::new(StaticProvider::new_minimal(
+                        access_key.expose().clone(),
+                        secret_key.expose().clone(),
+                    ))
+                }
+                Some(_) if credentials.has_keys() => {
+                    return Err(anyhow!("AWS credentials secret cannot be set with keys"));
+                }
+                Some(secret) => Arc::new(Cached::new(SecretProvider {
+                    secret: secret.clone(),
+                })),
+            },
+            Some(AuthConfig::AssumeRole(config)) => {
+                let source = Self::new(config.source.as_deref(), region)?;
+                let sts_client = StsClient::new_with(create_client()?, source, region.clone());
+                let provider = StsAssumeRoleSessionCredentialsProvider::new(
+                    sts_client,
+                    config.role_arn.clone(),
+                    session_name(&config.session_name),
+                    config.external_id.clone(),
+                    config
+                        .duration
+                        .map(|duration| Duration::seconds(duration as i64)),
+                    None,
+                    None,
+                );
+                Arc::new(Cached::new(provider))
+            }
+            Some(AuthConfig::WebIdentity(config)) => {
+                let (role_arn, token_file) = config.resolve()?;
+                let client = rusoto_core::Client::new_not_signing(create_client()?);
+                let provider = WebIdentityProvider {
+                    sts_client: StsClient::new_with_client(client, region.clone()),
+                    role_arn,
+                    token_file,
+                    session_name: session_name(&config.session_name),
+                };
+                Arc::new(Cached::new(provider))
+            }
+        };
+        Ok(CredentialsProvider(provider))
+    }
+}
+
+/// Caches the credentials of a provider until shortly before they expire
+///
+/// Unlike the Rusoto `AutoRefreshingProvider`, failures are not cached, so
+/// that a transient STS or Kubernetes failure is retried on the next call.
+struct Cached<P> {
+    provider: P,
+    credentials: Mutex<Option<AwsCredentials>>,
+}
+
+impl<P> Cached<P> {
+    fn new(provider: P) -> Self {
+        Cached {
+            provider,
+            credentials: Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl<P: ProvideAwsCredentials + Send + Sync> ProvideAwsCredentials for Cached<P> {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let mut cached = self.credentials.lock().await;
+        if let Some(credentials) = cached.as_ref() {
+            let expires_soon = matches!(
+                credentials.expires_at(),
+                Some(expires_at) if *expires_at - Duration::minutes(REFRESH_MARGIN) <= Utc::now()
+            );
+            if !expires_soon {
+                return Ok(credentials.clone());
+            }
+        }
+        let credentials = self.provider.credentials().await?;
+        *cached = Some(credentials.clone());
+        Ok(credentials)
+    }
+}
+
+/// Reads access keys from a Kubernetes Secret
+struct SecretProvider {
+    secret: SecretRef,
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for SecretProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let error = |e: &dyn std::fmt::Display| {
+            CredentialsError::new(format!(
+                "Unable to read AWS credentials from Secret {} : {}",
+                self.secret.name, e
+            ))
+        };
+        let namespace = match &self.secret.namespace {
+            Some(namespace) => namespace.clone(),
+            None => tokio::fs::read_to_string(NAMESPACE_PATH)
+                .await
+                .map_err(|e| error(&e))?,
+        };
+        let client = Client::try_default().await.map_err(|e| error(&e))?;
+        let api: Api<Secret> = Api::namespaced(client, namespace.trim());
+        let secret = api.get(&self.secret.name).await.map_err(|e| error(&e))?;
+        let data = secret.data.unwrap_or_default();
+        let read = |key: &str| -> Result<String, CredentialsError> {
+            let value = data
+                .get(key)
+                .ok_or_else(|| error(&format!("no {} key", key)))?;
+            String::from_utf8(value.0.clone())
+                .map(|value| String::from(value.trim()))
+                .map_err(|e| error(&e))
+        };
+        let access_key = read(self.secret.access_key.as_deref().unwrap_or("access_key"))?;
+        let secret_key = read(self.secret.secret_key.as_deref().unwrap_or("secret_key"))?;
+        Ok(AwsCredentials::new(
+            access_key,
+            secret_key,
+            None,
+            Some(Utc::now() + Duration::minutes(SECRET_REFRESH + REFRESH_MARGIN)),
+        ))
+    }
+}
+
+/// Assumes a role with a web identity token, read from its file on every
+/// refresh as the token is rotated by Kubernetes
+struct WebIdentityProvider {
+    sts_client: StsClient,
+    role_arn: String,
+    token_file: String,
+    session_name: String,
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for WebIdentityProvider {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        let token = tokio::fs::read_to_string(&self.token_file)
+            .await
+            .map_err(|e| {
+                CredentialsError::new(format!(
+                    "Unable to read web identity token {} : {}",
+                    self.token_file, e
+                ))
+            })?;
+        let request = AssumeRoleWithWebIdentityRequest {
+            role_arn: self.role_arn.clone(),
+            role_session_name: self.session_name.clone(),
+            web_identity_token: String::from(token.trim()),
+            ..Default::default()
+        };
+        let response = self
+            .sts_client
+            .assume_role_with_web_identity(request)
+            .await
+            .map_err(|e| {
+                CredentialsError::new(format!("Unable to assume {} : {}", self.role_arn, e))
+            })?;
+        let credentials = response
+            .credentials
+            .ok_or_else(|| CredentialsError::new("No credentials in STS response"))?;
+        AwsCredentials::new_for_credentials(credentials)
+    }
+}
+
+fn session_name(session_name: &Option<String>) -> String {
+    session_name
+        .clone()
+        .unwrap_or_else(|| String::from(DEFAULT_SESSION_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AssumeRoleConfig, AuthConfig, Cached, CredentialsProvider, WebIdentityConfig};
+    use async_trait::async_trait;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use indoc::indoc;
+    use rusoto_core::Region;
+    use rusoto_credential::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
+    use std::convert::Infallible;
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Answers STS `AssumeRole` and `AssumeRoleWithWebIdentity` calls,
+    /// recording the request bodies
+    async fn mock_sts() -> (Region, Arc<Mutex<Vec<String>>>) {
+        let requests: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let recorded = recorded.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let body = String::from_utf8(body.to_vec()).unwrap();
+                        let action = match body.contains("Action=AssumeRoleWithWebIdentity") {
+                            true => "AssumeRoleWithWebIdentity",
+                            false => "AssumeRole",
+                        };
+                        recorded.lock().unwrap().push(body);
+                        let xml = indoc!(
+                            "
+                            <ACTIONResponse>
+                              <ACTIONResult>
+                                <Credentials>
+                                  <AccessKeyId>ACTION</AccessKeyId>
+                                  <SecretAccessKey>secret</SecretAccessKey>
+                                  <SessionToken>token</SessionToken>
+                                  <Expiration>2099-01-01T00:00:00Z</Expiration>
+                                </Credentials>
+                              </ACTIONResult>
+                              <ResponseMetadata>
+                                <RequestId>request</RequestId>
+                              </ResponseMetadata>
+                            </ACTIONResponse>
+                            "
+                        )
+                        .replace("ACTION", action);
+                        Ok::<_, Infallible>(Response::new(Body::from(xml)))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let region = Region::Custom {
+            name: String::from("us-east-1"),
+            endpoint: format!("http://{}", server.local_addr()),
+        };
+        tokio::spawn(server);
+        (region, requests)
+    }
+
+    #[test]
+    fn parse_auth_test() -> anyhow::Result<()> {
+        let config_str = indoc!(
+            "
+            assume_role:
+              role_arn: arn:aws:iam::123456789012:role/cert-sync
+              external_id: cert-sync
+              source:
+                web_identity:
+                  token_file: /var/run/secrets/eks.amazonaws.com/serviceaccount/token
+            "
+        );
+        let auth: AuthConfig = serde_yaml::from_str(config_str)?;
+        let web_identity = WebIdentityConfig {
+            token_file: Some(String::from(
+                "/var/run/secrets/eks.amazonaws.com/serviceaccount/token",
+            )),
+            ..Default::default()
+        };
+        assert_eq!(
+            auth,
+            AuthConfig::AssumeRole(AssumeRoleConfig {
+                role_arn: String::from("arn:aws:iam::123456789012:role/cert-sync"),
+                external_id: Some(String::from("cert-sync")),
+                session_name: None,
+                duration: None,
+                source: Some(Box::new(AuthConfig::WebIdentity(web_identity))),
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_static_credentials() -> anyhow::Result<()> {
+        let mut secret_key_file = tempfile::NamedTempFile::new()?;
+        writeln!(secret_key_file, "secret")?;
+        let config_str = indoc!(
+            "
+            static:
+              access_key: key
+              secret_key_file: SECRET_KEY_FILE
+            "
+        )
+        .replace("SECRET_KEY_FILE", &secret_key_file.path().to_string_lossy());
+        let auth: AuthConfig = serde_yaml::from_str(&config_str)?;
+        assert!(format!("{:?}", auth).contains("access_key: Some([redacted])"));
+        let credentials = CredentialsProvider::new(Some(&auth), &Region::UsEast1)?
+            .credentials()
+            .await?;
+        assert_eq!(credentials.aws_access_key_id(), "key");
+        assert_eq!(credentials.aws_secret_access_key(), "secret");
+
+        let both: AuthConfig = serde_yaml::from_str(indoc!(
+            "
+            static:
+              access_key: key
+              secret:
+                name: aws-credentials
+            "
+        ))?;
+        assert!(CredentialsProvider::new(Some(&both), &Region::UsEast1).is_err());
+        let missing: AuthConfig = serde_yaml::from_str("static: { access_key: key }")?;
+        assert!(CredentialsProvider::new(Some(&missing), &Region::UsEast1).is_err());
+        Ok(())
+    }
+
+    /// Fails on its first call only
+    struct FlakyProvider {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ProvideAwsCredentials for FlakyProvider {
+        async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+            match self.calls.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(CredentialsError::new("unavailable")),
+                _ => Ok(AwsCredentials::new("key", "secret", None, None)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_failed_credentials() -> anyhow::Result<()> {
+        let provider = Cached::new(FlakyProvider {
+            calls: AtomicUsize::new(0),
+        });
+        assert!(provider.credentials().await.is_err());
+        assert_eq!(provider.credentials().await?.aws_access_key_id(), "key");
+        provider.credentials().await?;
+        assert_eq!(provider.provider.calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn assume_role_with_web_identity() -> anyhow::Result<()> {
+        let (region, requests) = mock_sts().await;
+        let mut token_file = tempfile::NamedTempFile::new()?;
+        writeln!(token_file, "jwt")?;
+        let config_str = indoc!(
+            "
+            assume_role:
+              role_arn: arn:aws:iam::210987654321:role/target
+              external_id: external
+              session_name: session
+              source:
+                web_identity:
+                  role_arn: arn:aws:iam::123456789012:role/cert-sync
+                  token_file: TOKEN_FILE
+            "
+        )
+        .replace("TOKEN_FILE", &token_file.path().to_string_lossy());
+        let auth: AuthConfig = serde_yaml::from_str(&config_str)?;
+        let provider = CredentialsProvider::new(Some(&auth), &region)?;
+
+        let credentials = provider.credentials().await?;
+        assert_eq!(credentials.aws_access_key_id(), "AssumeRole");
+        assert_eq!(credentials.token().as_deref(), Some("token"));
+        // Credentials are cached until they expire
+        provider.credentials().await?;
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains("Action=AssumeRoleWithWebIdentity"));
+        assert!(requests[0].contains("WebIdentityToken=jwt"));
+        assert!(requests[0].contains("RoleSessionName=cert-sync"));
+        assert!(requests[1].starts_with("Action=AssumeRole&"));
+        assert!(requests[1].contains("ExternalId=external"));
+        assert!(requests[1].contains("RoleSessionName=session"));
+        Ok(())
+    }
+}
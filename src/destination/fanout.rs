@@ -0,0 +1,102 @@
+use super::{into_result, Destination, PublishError, Published};
+use crate::common::Envelope;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+
+/// Forwards the certificates of a source to every destination it is routed
+/// to
+///
+/// A failing destination does not prevent the others from receiving the
+/// certificate, the failures are reported together once all are done.
+/// Published certificates belong to a single destination, so they can only
+/// be attached, detached and removed through that destination.
+pub struct FanOut<'a> {
+    destinations: Vec<&'a dyn Destination>,
+}
+
+impl<'a> FanOut<'a> {
+    pub fn new(destinations: Vec<&'a dyn Destination>) -> Self {
+        FanOut { destinations }
+    }
+
+    /// Copies the envelope for all the destinations but the last one, which
+    /// receives the original
+    fn copies(&self, envelope: Envelope) -> Vec<(&'a dyn Destination, Envelope)> {
+        let mut copies: Vec<(&'a dyn Destination, Envelope)> = vec![];
+        if let Some((last, others)) = self.destinations.split_last() {
+            for destination in others {
+                copies.push((*destination, envelope.clone()));
+            }
+            copies.push((*last, envelope));
+        }
+        copies
+    }
+}
+
+#[async_trait]
+impl<'a> Destination for FanOut<'a> {
+    fn name(&self) -> String {
+        let names: Vec<String> = self
+            .destinations
+            .iter()
+            .map(|destination| destination.name())
+            .collect();
+        names.join(", ")
+    }
+
+    async fn publish(&self, envelope: Envelope) -> Result<(), PublishError> {
+        let mut errors: Vec<(String, PublishError)> = vec![];
+        for (destination, envelope) in self.copies(envelope) {
+            if let Err(e) = destination.publish(envelope).await {
+                errors.push((destination.name(), e));
+            }
+        }
+        PublishError::from_parts(errors)
+    }
+
+    async fn unpublish(&self, envelope: Envelope) -> anyhow::Result<()> {
+        let mut errors: Vec<String> = vec![];
+        for (destination, envelope) in self.copies(envelope) {
+            if let Err(e) = destination.unpublish(envelope).await {
+                errors.push(format!("{} : {}", destination.name(), e));
+            }
+        }
+        into_result(errors)
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<Published>> {
+        let mut published: Vec<Published> = vec![];
+        for destination in &self.destinations {
+            published.extend(destination.list().await?);
+        }
+        Ok(published)
+    }
+
+    async fn targets(&self, envelope: &Envelope) -> anyhow::Result<Vec<String>> {
+        let mut targets: Vec<String> = vec![];
+        for destination in &self.destinations {
+            targets.extend(destination.targets(envelope).await?);
+        }
+        Ok(targets)
+    }
+
+    async fn attach(&self, published: &Published, _target: &str) -> anyhow::Result<()> {
+        Err(not_routable(published))
+    }
+
+    async fn detach(&self, published: &Published, _target: &str) -> anyhow::Result<()> {
+        Err(not_routable(published))
+    }
+
+    async fn remove(&self, published: &Published) -> anyhow::Result<()> {
+        Err(not_routable(published))
+    }
+}
+
+fn not_routable(published: &Published) -> anyhow::Error {
+    anyhow!(
+        "Unable to tell which destination holds {}, use it directly",
+        published.reference
+    )
+}
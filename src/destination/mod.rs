@@ -1,12 +1,114 @@
 mod aws;
+mod aws_credentials;
+mod fanout;
 
-use super::common::TLS;
+use super::common::{Compatibility, Envelope};
 
+use anyhow::anyhow;
 use async_trait::async_trait;
 pub use aws::AcmAlbDestination;
+pub use fanout::FanOut;
+
+/// Represents a certificate as currently stored in a destination
+///
+/// # Fields
+///
+/// * `id` - The identifier of the source object the certificate comes from
+/// * `reference` - The destination reference of the certificate, e.g. an ARN
+/// * `fingerprint` - The SHA-256 fingerprint of the stored certificate
+/// * `targets` - The targets the certificate is attached to, e.g. listeners
+/// * `holder` - The name of the part of the destination storing the
+///   certificate, e.g. an AWS target, when the destination has several
+#[derive(Debug, Clone, PartialEq)]
+pub struct Published {
+    pub id: String,
+    pub reference: String,
+    pub fingerprint: Option<String>,
+    pub targets: Vec<String>,
+    pub holder: Option<String>,
+}
+
+/// Errors of a destination publishing a certificate
+#[derive(Debug, thiserror::Error)]
+pub enum PublishError {
+    /// The destination refuses the certificate, publishing it again would
+    /// fail the same way
+    #[error("certificate {id} is rejected : {reason}")]
+    Rejected { id: String, reason: String },
+    /// The certificate is stored but could not be attached to some targets
+    #[error("certificate {reference} is not attached to every target : {}", .errors.join(", "))]
+    Unattached {
+        reference: String,
+        errors: Vec<String>,
+    },
+    /// A call to the destination failed, e.g. on a network error
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+    /// Some parts of the destination failed, by name
+    #[error("{}", join_parts(.0))]
+    Parts(Vec<(String, PublishError)>),
+}
+
+impl PublishError {
+    /// Tells whether publishing the same certificate again cannot succeed
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            PublishError::Rejected { .. } => true,
+            PublishError::Parts(parts) => parts.iter().all(|(_, e)| e.is_permanent()),
+            PublishError::Unattached { .. } | PublishError::Failed(_) => false,
+        }
+    }
+
+    /// Builds a `Parts` error out of the parts which failed to publish, keeping
+    /// their own errors so that `is_permanent` can inspect each of them
+    fn from_parts(parts: Vec<(String, PublishError)>) -> Result<(), PublishError> {
+        match parts.is_empty() {
+            true => Ok(()),
+            false => Err(PublishError::Parts(parts)),
+        }
+    }
+}
 
 #[async_trait]
-pub trait Destination {
+pub trait Destination: Send + Sync {
     fn name(&self) -> String;
-    async fn publish(&self, tls: TLS) -> anyhow::Result<()>;
+    async fn publish(&self, envelope: Envelope) -> Result<(), PublishError>;
+    /// Removes a certificate whose source has been deleted
+    async fn unpublish(&self, envelope: Envelope) -> anyhow::Result<()>;
+    /// Lists the certificates managed by cert-sync in this destination
+    async fn list(&self) -> anyhow::Result<Vec<Published>>;
+    /// Returns the targets a certificate should be attached to
+    async fn targets(&self, envelope: &Envelope) -> anyhow::Result<Vec<String>>;
+    async fn attach(&self, published: &Published, target: &str) -> anyhow::Result<()>;
+    async fn detach(&self, published: &Published, target: &str) -> anyhow::Result<()>;
+    /// Detaches a certificate from all its targets and deletes it
+    async fn remove(&self, published: &Published) -> anyhow::Result<()>;
+    /// Tells whether the certificate should be published at all, e.g. as it
+    /// matches the filters of the destination
+    fn accepts(&self, _envelope: &Envelope) -> bool {
+        true
+    }
+    /// Returns the parts of the destination to reconcile on their own, e.g.
+    /// the accounts of a destination spanning several of them. Empty when the
+    /// destination is reconciled as a whole
+    fn partitions(&self) -> Vec<&dyn Destination> {
+        vec![]
+    }
+}
+
+fn join_parts(parts: &[(String, PublishError)]) -> String {
+    let parts: Vec<String> = parts
+        .iter()
+        .map(|(name, e)| format!("{} : {}", name, e))
+        .collect();
+    parts.join(", ")
+}
+
+/// Turns the messages of the parts which failed, e.g. to unpublish, into a
+/// single error, or into `Ok` when none failed
+fn into_result(errors: Vec<String>) -> anyhow::Result<()> {
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!("{}", errors.join(", "))),
+    }
 }
@@ -3,8 +3,19 @@ extern crate log;
 
 mod common;
 mod destination;
+mod pipeline;
+mod queue;
+mod reconciler;
 mod source;
+mod status;
+mod throttle;
 
-pub use common::TLS;
-pub use destination::{AcmAlbDestination, Destination};
-pub use source::{SecretSource, Source};
+pub use common::{
+    CertificateInfo, ChainError, Compatibility, Envelope, Issue, KeyAlgorithm, KeyError, Metadata,
+    ObjectRef, PemError, Redacted, TlsError, TLS,
+};
+pub use destination::{AcmAlbDestination, Destination, FanOut, PublishError, Published};
+pub use pipeline::{DestinationFactory, Pipeline, Registry, SourceFactory};
+pub use queue::WorkQueue;
+pub use reconciler::{Action, Plan, Reconciler};
+pub use source::{CertManagerSource, FileSource, Inventory, SecretSource, Source, VaultSource};
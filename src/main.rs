@@ -1,8 +1,7 @@
 #[macro_use]
 extern crate log;
 
-use anyhow::anyhow;
-use cert_sync::{AcmAlbDestination, SecretSource, Source};
+use cert_sync::{Pipeline, Registry};
 use std::io::prelude::*;
 use std::{fs::File, path::Path};
 
@@ -26,8 +25,7 @@ fn retrieve_config() -> anyhow::Result<String> {
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
     let config = retrieve_config()?;
-    let source = SecretSource::new(&config).await?;
-    let destination = AcmAlbDestination::new(&config)?;
-    source.receive(&destination).await?;
-    Err(anyhow!("Abort program due to unknown error"))
+    let dry_run = std::env::args().any(|arg| arg == "--dry-run");
+    let pipeline = Pipeline::build(&config, &Registry::default(), dry_run).await?;
+    pipeline.run(&config).await
 }
@@ -0,0 +1,570 @@
+use super::destination::{AcmAlbDestination, Destination, FanOut};
+use super::queue::WorkQueue;
+use super::reconciler::Reconciler;
+use super::source::{CertManagerSource, FileSource, SecretSource, Source, VaultSource};
+use super::status::Status;
+
+use anyhow::anyhow;
+use futures::future::{try_join_all, BoxFuture};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Root sections read by every source and destination, next to their own
+const SHARED_SECTIONS: [&str; 1] = ["tls"];
+
+/// Builds a source out of a configuration holding its section and the
+/// shared sections only
+pub type SourceFactory = fn(String) -> BoxFuture<'static, anyhow::Result<Box<dyn Source>>>;
+
+/// Builds a destination out of a configuration holding its section and the
+/// shared sections only, and whether to run dry
+pub type DestinationFactory =
+    fn(String, bool) -> BoxFuture<'static, anyhow::Result<Arc<dyn Destination>>>;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct PipelineRootConfig {
+    sources: Option<Vec<EndpointConfig>>,
+    destinations: Option<Vec<EndpointConfig>>,
+    routes: Option<Vec<RouteConfig>>,
+}
+
+/// A source or a destination. Its settings are the ones of the matching
+/// section of a single source or destination configuration, e.g. `aws`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct EndpointConfig {
+    /// Unique name, defaults to the type
+    name: Option<String>,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(flatten)]
+    settings: BTreeMap<String, Value>,
+}
+
+impl EndpointConfig {
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.kind)
+    }
+}
+
+/// Sends the certificates of a source to some destinations
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct RouteConfig {
+    source: String,
+    destinations: Vec<String>,
+}
+
+/// The sources and destinations to build, with the destinations of each
+/// source by index and the shared sections by name
+#[derive(Debug, PartialEq)]
+struct Layout {
+    sources: Vec<EndpointConfig>,
+    destinations: Vec<EndpointConfig>,
+    routes: Vec<Vec<usize>>,
+    shared: BTreeMap<String, Value>,
+}
+
+/// The source and destination types which can be declared in the
+/// configuration, by type name
+pub struct Registry {
+    sources: HashMap<String, (String, SourceFactory)>,
+    destinations: HashMap<String, (String, DestinationFactory)>,
+}
+
+impl Default for Registry {
+    /// Registers the types shipped with cert-sync
+    fn default() -> Self {
+        let mut registry = Registry::new();
+        registry.register_source("kubernetes", "kubernetes", kubernetes_source);
+        registry.register_source("files", "files", file_source);
+        registry.register_source("vault", "vault", vault_source);
+        registry.register_destination("aws", "aws", aws_destination);
+        registry
+    }
+}
+
+impl Registry {
+    /// Creates a registry without any type
+    pub fn new() -> Self {
+        Registry {
+            sources: HashMap::new(),
+            destinations: HashMap::new(),
+        }
+    }
+
+    /// Registers a source type, whose factory reads its settings under
+    /// `section`
+    pub fn register_source(&mut self, kind: &str, section: &str, factory: SourceFactory) {
+        self.sources
+            .insert(String::from(kind), (String::from(section), factory));
+    }
+
+    /// Registers a destination type, whose factory reads its settings under
+    /// `section`
+    pub fn register_destination(&mut self, kind: &str, section: &str, factory: DestinationFactory) {
+        self.destinations
+            .insert(String::from(kind), (String::from(section), factory));
+    }
+
+    async fn build_source(
+        &self,
+        endpoint: &EndpointConfig,
+        shared: &BTreeMap<String, Value>,
+    ) -> anyhow::Result<Box<dyn Source>> {
+        let (section, factory) = self
+            .sources
+            .get(&endpoint.kind)
+            .ok_or_else(|| anyhow!("Unknown source type {}", endpoint.kind))?;
+        factory(section_config(section, &endpoint.settings, shared)?).await
+    }
+
+    async fn build_destination(
+        &self,
+        endpoint: &EndpointConfig,
+        shared: &BTreeMap<String, Value>,
+        dry_run: bool,
+    ) -> anyhow::Result<Arc<dyn Destination>> {
+        let (section, factory) = self
+            .destinations
+            .get(&endpoint.kind)
+            .ok_or_else(|| anyhow!("Unknown destination type {}", endpoint.kind))?;
+        factory(
+            section_config(section, &endpoint.settings, shared)?,
+            dry_run,
+        )
+        .await
+    }
+}
+
+/// The sources and destinations of a configuration, along with the routes
+/// between them
+pub struct Pipeline {
+    sources: Vec<Box<dyn Source>>,
+    destinations: Vec<Arc<dyn Destination>>,
+    routes: Vec<Vec<usize>>,
+    status: Arc<Status>,
+}
+
+impl Pipeline {
+    /// Builds the sources and destinations declared in the configuration
+    ///
+    /// Without `sources` nor `destinations`, the sections of a single source
+    /// and of the `aws` destination are used, as before pipelines existed.
+    pub async fn build(
+        config_str: &str,
+        registry: &Registry,
+        dry_run: bool,
+    ) -> anyhow::Result<Self> {
+        let layout = layout(config_str)?;
+        let mut sources: Vec<Box<dyn Source>> = vec![];
+        for endpoint in &layout.sources {
+            info!("Build source {} of type {}", endpoint.name(), endpoint.kind);
+            sources.push(registry.build_source(endpoint, &layout.shared).await?);
+        }
+        let mut destinations: Vec<Arc<dyn Destination>> = vec![];
+        for endpoint in &layout.destinations {
+            info!(
+                "Build destination {} of type {}",
+                endpoint.name(),
+                endpoint.kind
+            );
+            destinations.push(
+                registry
+                    .build_destination(endpoint, &layout.shared, dry_run)
+                    .await?,
+            );
+        }
+        Ok(Pipeline {
+            sources,
+            destinations,
+            routes: layout.routes,
+            status: Arc::new(Status::default()),
+        })
+    }
+
+    /// Forwards the events of every source to its destinations, and
+    /// reconciles every destination with its sources, until an error occurs
+    pub async fn run(&self, config_str: &str) -> anyhow::Result<()> {
+        // Changes from the sources and reconciliations go through a single
+        // queue per destination, so that an object is never changed twice at
+        // the same time
+        let mut queues: Vec<WorkQueue> = vec![];
+        for destination in &self.destinations {
+            queues.push(WorkQueue::new(
+                destination.as_ref(),
+                &self.status,
+                config_str,
+            )?);
+        }
+        let fanouts: Vec<FanOut> = self
+            .routes
+            .iter()
+            .map(|route| {
+                FanOut::new(
+                    route
+                        .iter()
+                        .map(|index| &queues[*index] as &dyn Destination)
+                        .collect(),
+                )
+            })
+            .collect();
+        let mut reconcilers: Vec<Reconciler> = vec![];
+        for (index, queue) in queues.iter().enumerate() {
+            let sources: Vec<&dyn Source> = self
+                .sources
+                .iter()
+                .zip(&self.routes)
+                .filter(|(_, route)| route.contains(&index))
+                .map(|(source, _)| source.as_ref())
+                .collect();
+            if !sources.is_empty() {
+                reconcilers.push(Reconciler::new(sources, queue, config_str)?);
+            }
+        }
+        let mut tasks: Vec<Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>>> = vec![];
+        for (source, fanout) in self.sources.iter().zip(&fanouts) {
+            tasks.push(source.receive(fanout));
+        }
+        for queue in &queues {
+            tasks.push(Box::pin(queue.run()));
+        }
+        for reconciler in &reconcilers {
+            tasks.push(Box::pin(reconciler.run()));
+        }
+        tasks.push(Box::pin(self.status.clone().serve(config_str)));
+        try_join_all(tasks).await?;
+        Err(anyhow!("Abort program due to unknown error"))
+    }
+}
+
+/// Resolves the sources, destinations and routes of the configuration
+fn layout(config_str: &str) -> anyhow::Result<Layout> {
+    let config = parse_config(config_str)?;
+    let (sources, destinations) = match (config.sources, config.destinations) {
+        (None, None) => legacy_endpoints(config_str)?,
+        (Some(sources), Some(destinations)) => (sources, destinations),
+        _ => return Err(anyhow!("Both sources and destinations must be declared")),
+    };
+    check_unique_names(&sources)?;
+    check_unique_names(&destinations)?;
+    let position = |endpoints: &[EndpointConfig], name: &str| {
+        endpoints
+            .iter()
+            .position(|endpoint| endpoint.name() == name)
+            .ok_or_else(|| anyhow!("Unknown source or destination {} in routes", name))
+    };
+    let routes = match config.routes {
+        Some(route_configs) => {
+            let mut routes: Vec<Vec<usize>> = vec![vec![]; sources.len()];
+            for route in route_configs {
+                let source = position(&sources, &route.source)?;
+                for name in &route.destinations {
+                    let destination = position(&destinations, name)?;
+                    if !routes[source].contains(&destination) {
+                        routes[source].push(destination);
+                    }
+                }
+            }
+            routes
+        }
+        None => vec![(0..destinations.len()).collect(); sources.len()],
+    };
+    for (source, route) in sources.iter().zip(&routes) {
+        if route.is_empty() {
+            warn!("Source {} is not routed to any destination", source.name());
+        }
+    }
+    let root: BTreeMap<String, Value> = serde_yaml::from_str(config_str)?;
+    let shared = root
+        .into_iter()
+        .filter(|(key, _)| SHARED_SECTIONS.contains(&key.as_str()))
+        .collect();
+    Ok(Layout {
+        sources,
+        destinations,
+        routes,
+        shared,
+    })
+}
+
+/// Reads the single source and the `aws` destination of a configuration
+/// without pipeline. Files and Vault sources take precedence over Kubernetes.
+fn legacy_endpoints(
+    config_str: &str,
+) -> anyhow::Result<(Vec<EndpointConfig>, Vec<EndpointConfig>)> {
+    let root: BTreeMap<String, Value> = serde_yaml::from_str(config_str)?;
+    let endpoint = |kind: &str| EndpointConfig {
+        name: None,
+        kind: String::from(kind),
+        settings: match root.get(kind) {
+            Some(Value::Mapping(mapping)) => mapping
+                .iter()
+                .filter_map(|(key, value)| Some((String::from(key.as_str()?), value.clone())))
+                .collect(),
+            _ => BTreeMap::new(),
+        },
+    };
+    let source = ["files", "vault"]
+        .iter()
+        .find(|kind| root.contains_key(**kind))
+        .map(|kind| endpoint(kind))
+        .unwrap_or_else(|| endpoint("kubernetes"));
+    Ok((vec![source], vec![endpoint("aws")]))
+}
+
+fn check_unique_names(endpoints: &[EndpointConfig]) -> anyhow::Result<()> {
+    for (index, endpoint) in endpoints.iter().enumerate() {
+        if endpoints[..index]
+            .iter()
+            .any(|other| other.name() == endpoint.name())
+        {
+            return Err(anyhow!(
+                "Name {} is used several times, set a distinct name",
+                endpoint.name()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the settings of an endpoint under `section`, along with the shared
+/// sections, as read by the factories
+fn section_config(
+    section: &str,
+    settings: &BTreeMap<String, Value>,
+    shared: &BTreeMap<String, Value>,
+) -> anyhow::Result<String> {
+    let mut root: BTreeMap<&str, Value> = shared
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.clone()))
+        .collect();
+    root.insert(section, serde_yaml::to_value(settings)?);
+    Ok(serde_yaml::to_string(&root)?)
+}
+
+/// Secrets, or cert-manager Certificates with `resource: certificate`
+fn kubernetes_source(config: String) -> BoxFuture<'static, anyhow::Result<Box<dyn Source>>> {
+    Box::pin(async move {
+        let source: Box<dyn Source> = match CertManagerSource::is_configured(&config)? {
+            true => Box::new(CertManagerSource::new(&config).await?),
+            false => Box::new(SecretSource::new(&config).await?),
+        };
+        Ok(source)
+    })
+}
+
+fn file_source(config: String) -> BoxFuture<'static, anyhow::Result<Box<dyn Source>>> {
+    Box::pin(async move {
+        let source: Box<dyn Source> = Box::new(FileSource::new(&config)?);
+        Ok(source)
+    })
+}
+
+fn vault_source(config: String) -> BoxFuture<'static, anyhow::Result<Box<dyn Source>>> {
+    Box::pin(async move {
+        let source: Box<dyn Source> = Box::new(VaultSource::new(&config)?);
+        Ok(source)
+    })
+}
+
+fn aws_destination(
+    config: String,
+    dry_run: bool,
+) -> BoxFuture<'static, anyhow::Result<Arc<dyn Destination>>> {
+    Box::pin(async move {
+        let destination: Arc<dyn Destination> =
+            Arc::new(AcmAlbDestination::new(&config)?.with_dry_run(dry_run));
+        Ok(destination)
+    })
+}
+
+/// Get config from file
+fn parse_config(config_str: &str) -> anyhow::Result<PipelineRootConfig> {
+    let config: PipelineRootConfig = serde_yaml::from_str(config_str)?;
+    debug!("Pipeline config : {:?}", config);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{layout, Pipeline, Registry};
+    use crate::common::TlsConfig;
+    use crate::source::{Inventory, Source};
+    use crate::Destination;
+    use async_trait::async_trait;
+    use futures::future::BoxFuture;
+    use indoc::indoc;
+
+    struct NamedSource(String);
+
+    #[async_trait]
+    impl Source for NamedSource {
+        fn name(&self) -> String {
+            self.0.clone()
+        }
+
+        async fn receive(&self, _destination: &dyn Destination) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn list(&self) -> anyhow::Result<Inventory> {
+            Ok(Inventory::default())
+        }
+    }
+
+    fn named_source(config: String) -> BoxFuture<'static, anyhow::Result<Box<dyn Source>>> {
+        Box::pin(async move {
+            let source: Box<dyn Source> = Box::new(NamedSource(config));
+            Ok(source)
+        })
+    }
+
+    #[test]
+    fn layout_routes() -> anyhow::Result<()> {
+        let config_str = indoc!(
+            "
+            sources:
+              - type: kubernetes
+                annotation: cert-sync.io/enabled
+              - name: certbot
+                type: files
+                directories: [/etc/letsencrypt/live]
+            destinations:
+              - name: prod
+                type: aws
+                region: [eu-west-3]
+              - name: cloudfront
+                type: aws
+                region: [us-east-1]
+            routes:
+              - source: kubernetes
+                destinations: [prod, cloudfront]
+              - source: certbot
+                destinations: [cloudfront]
+            "
+        );
+        let layout = layout(config_str)?;
+        assert_eq!(
+            vec!["kubernetes", "certbot"],
+            layout
+                .sources
+                .iter()
+                .map(|source| source.name())
+                .collect::<Vec<&str>>()
+        );
+        assert!(layout.sources[0].settings.contains_key("annotation"));
+        assert_eq!(vec![vec![0, 1], vec![1]], layout.routes);
+
+        // Every source goes to every destination without routes
+        let config_str = config_str.split("routes:").next().unwrap();
+        assert_eq!(
+            vec![vec![0, 1], vec![0, 1]],
+            super::layout(config_str)?.routes
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn layout_legacy_config() -> anyhow::Result<()> {
+        let layout = layout(indoc!(
+            "
+            aws:
+              region: [eu-west-3]
+            vault:
+              address: http://127.0.0.1:8200
+            "
+        ))?;
+        assert_eq!("vault", layout.sources[0].kind);
+        assert!(layout.sources[0].settings.contains_key("address"));
+        assert_eq!("aws", layout.destinations[0].kind);
+        assert_eq!(vec![vec![0]], layout.routes);
+        assert_eq!("kubernetes", super::layout("aws: {}")?.sources[0].kind);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_layouts() {
+        let duplicated = indoc!(
+            "
+            sources:
+              - type: kubernetes
+              - type: kubernetes
+            destinations: []
+            "
+        );
+        assert!(layout(duplicated).is_err());
+        let unknown = indoc!(
+            "
+            sources:
+              - type: kubernetes
+            destinations: []
+            routes:
+              - source: kubernetes
+                destinations: [prod]
+            "
+        );
+        assert!(layout(unknown).is_err());
+        assert!(layout("sources: []").is_err());
+    }
+
+    #[tokio::test]
+    async fn build_registered_types() -> anyhow::Result<()> {
+        let mut registry = Registry::new();
+        registry.register_source("named", "named", named_source);
+        let pipeline = Pipeline::build(
+            indoc!(
+                "
+                sources:
+                  - type: named
+                    value: 1
+                destinations: []
+                "
+            ),
+            &registry,
+            false,
+        )
+        .await?;
+        assert_eq!("---\nnamed:\n  value: 1", pipeline.sources[0].name());
+        let unknown = Pipeline::build(
+            "sources: [{type: other}]\ndestinations: []",
+            &registry,
+            false,
+        );
+        assert!(unknown.await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn build_with_shared_sections() -> anyhow::Result<()> {
+        let mut registry = Registry::new();
+        registry.register_source("named", "named", named_source);
+        let pipeline = Pipeline::build(
+            indoc!(
+                "
+                sources:
+                  - type: named
+                destinations: []
+                tls:
+                  common_name_fallback: true
+                "
+            ),
+            &registry,
+            false,
+        )
+        .await?;
+        let config_str = pipeline.sources[0].name();
+        assert!(config_str.contains("named:"));
+        assert_eq!(
+            TlsConfig::parse("tls: {common_name_fallback: true}")?,
+            TlsConfig::parse(&config_str)?
+        );
+        // Legacy configurations share it too
+        let layout = layout("aws: {}\ntls: {common_name_fallback: true}")?;
+        assert!(layout.shared.contains_key("tls"));
+        assert!(!layout.shared.contains_key("aws"));
+        Ok(())
+    }
+}
@@ -0,0 +1,727 @@
+use super::common::Envelope;
+use super::destination::{Destination, PublishError, Published};
+use super::reconciler::Action;
+use super::status::Status;
+use super::throttle::{Backoff, Clock, RetryConfig, SystemClock};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify};
+
+/// Certificates processed at once, unless overridden with `workers`
+const DEFAULT_WORKERS: usize = 4;
+
+/// Attempts of a change before giving up, unless overridden with
+/// `max_attempts`. Along with the default delays, the 9 retries of a change
+/// span 10 to 20 minutes depending on the jitter
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+/// Milliseconds before the first retry, unless overridden with
+/// `initial_delay`
+const DEFAULT_INITIAL_DELAY: u64 = 5_000;
+
+/// Milliseconds between two retries at most, unless overridden with
+/// `max_delay`
+const DEFAULT_MAX_DELAY: u64 = 300_000;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct QueueRootConfig {
+    queue: Option<QueueConfig>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct QueueConfig {
+    /// Certificates processed at once
+    workers: Option<usize>,
+    /// Attempts and delays of failed certificates
+    retry: Option<RetryConfig>,
+}
+
+/// A change received from a source, or computed by a reconciliation,
+/// waiting to be applied to the destination
+#[derive(Debug, Clone)]
+enum Operation {
+    Publish(Envelope),
+    Unpublish(Envelope),
+    /// The actions of a reconciliation on the certificate of one object, in
+    /// order
+    Reconcile(Vec<Action>),
+}
+
+impl Operation {
+    fn kind(&self) -> &'static str {
+        match self {
+            Operation::Publish(_) => "publish",
+            Operation::Unpublish(_) => "unpublish",
+            Operation::Reconcile(_) => "reconcile",
+        }
+    }
+
+    /// Identifies the source object the operation applies to
+    fn id(&self) -> String {
+        match self {
+            Operation::Publish(envelope) | Operation::Unpublish(envelope) => envelope.id(),
+            Operation::Reconcile(actions) => actions.first().map(Action::id).unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.kind(), self.id())
+    }
+}
+
+struct Item {
+    operation: Operation,
+    /// Failed attempts so far
+    failures: u32,
+    /// Not processed before this instant, when retrying
+    not_before: Instant,
+}
+
+/// What a worker should do next
+enum Next {
+    Process(String, Box<Item>),
+    /// Wait for a new item, or at most for the duration until a retry
+    Wait(Option<Duration>),
+}
+
+#[derive(Default)]
+struct State {
+    /// Latest operation of each object waiting to be processed
+    pending: HashMap<String, Item>,
+    /// Objects waiting, in order of arrival
+    order: VecDeque<String>,
+    /// Objects being processed by a worker
+    active: HashSet<String>,
+}
+
+impl State {
+    /// Adds an item, replacing the one of the same object still waiting. The
+    /// actions of a reconciliation never replace a change from a source,
+    /// which may be more recent than the listing they were computed from
+    fn push(&mut self, key: String, item: Item) {
+        let from_source = |item: &Item| !matches!(item.operation, Operation::Reconcile(_));
+        if !from_source(&item)
+            && matches!(self.pending.get(&key), Some(pending) if from_source(pending))
+        {
+            debug!(
+                "Drop {}, a change from the source is waiting",
+                item.operation
+            );
+            return;
+        }
+        if self.pending.insert(key.clone(), item).is_none() {
+            self.order.push_back(key);
+        }
+    }
+
+    /// Takes the oldest item ready to be processed, unless its object is
+    /// already being processed
+    fn pop(&mut self, now: Instant) -> Next {
+        let ready = self
+            .order
+            .iter()
+            .position(|key| !self.active.contains(key) && self.pending[key].not_before <= now);
+        if let Some(key) = ready.and_then(|index| self.order.remove(index)) {
+            let item = self.pending.remove(&key).unwrap();
+            self.active.insert(key.clone());
+            return Next::Process(key, Box::new(item));
+        }
+        let next_retry = self
+            .pending
+            .iter()
+            .filter(|(key, _)| !self.active.contains(*key))
+            .map(|(_, item)| item.not_before.saturating_duration_since(now))
+            .min();
+        Next::Wait(next_retry)
+    }
+}
+
+/// Receives the changes of the sources routed to a destination, along with
+/// the actions of its reconciliations, and applies them with several workers
+///
+/// Changes are keyed by source object, only the latest one of each object is
+/// kept while it waits, and a single worker processes an object at a time. A
+/// failed change is retried after a delay, unless a newer one arrived
+/// meanwhile, until it runs out of attempts. Changes which cannot succeed,
+/// and the ones out of attempts, are reported to the status.
+pub struct WorkQueue<'a> {
+    destination: &'a dyn Destination,
+    status: &'a Status,
+    workers: usize,
+    backoff: Backoff,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+impl<'a> WorkQueue<'a> {
+    pub fn new(
+        destination: &'a dyn Destination,
+        status: &'a Status,
+        config_str: &str,
+    ) -> anyhow::Result<Self> {
+        Ok(WorkQueue::with_clock(
+            destination,
+            status,
+            parse_config(config_str)?.queue,
+            Arc::new(SystemClock),
+        ))
+    }
+
+    fn with_clock(
+        destination: &'a dyn Destination,
+        status: &'a Status,
+        config: Option<QueueConfig>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let workers = config
+            .as_ref()
+            .and_then(|config| config.workers)
+            .unwrap_or(DEFAULT_WORKERS)
+            .max(1);
+        let retry = config
+            .and_then(|config| config.retry)
+            .unwrap_or_default()
+            .or(RetryConfig::new(
+                DEFAULT_MAX_ATTEMPTS,
+                DEFAULT_INITIAL_DELAY,
+                DEFAULT_MAX_DELAY,
+            ));
+        WorkQueue {
+            destination,
+            status,
+            workers,
+            backoff: Backoff::new(Some(&retry), clock.clone()),
+            clock,
+            state: Mutex::new(State::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Processes the queued changes forever
+    pub async fn run(&self) -> anyhow::Result<()> {
+        join_all((0..self.workers).map(|_| self.work())).await;
+        Ok(())
+    }
+
+    async fn work(&self) {
+        loop {
+            let (key, item) = self.next().await;
+            self.process(key, item).await;
+        }
+    }
+
+    /// Queues the actions of a reconciliation on the certificate of one
+    /// object, which are applied in order
+    pub async fn reconcile(&self, actions: Vec<Action>) {
+        if !actions.is_empty() {
+            self.push(Operation::Reconcile(actions)).await;
+        }
+    }
+
+    async fn push(&self, operation: Operation) {
+        let key = operation.id();
+        let item = Item {
+            operation,
+            failures: 0,
+            not_before: self.clock.now(),
+        };
+        self.state.lock().await.push(key, item);
+        self.notify.notify();
+    }
+
+    /// Waits for an item to process
+    async fn next(&self) -> (String, Box<Item>) {
+        loop {
+            let next = self.state.lock().await.pop(self.clock.now());
+            match next {
+                Next::Process(key, item) => {
+                    // Other workers may have been waiting for more items
+                    self.notify.notify();
+                    return (key, item);
+                }
+                Next::Wait(Some(delay)) => {
+                    tokio::select! {
+                        _ = self.notify.notified() => {}
+                        _ = self.clock.sleep(delay) => {}
+                    }
+                }
+                Next::Wait(None) => self.notify.notified().await,
+            }
+        }
+    }
+
+    /// Applies the change, then queues it again when it failed and may
+    /// succeed later on
+    async fn process(&self, key: String, mut item: Box<Item>) {
+        let res = match item.operation.clone() {
+            Operation::Publish(envelope) => self.destination.publish(envelope).await,
+            Operation::Unpublish(envelope) => self
+                .destination
+                .unpublish(envelope)
+                .await
+                .map_err(PublishError::from),
+            Operation::Reconcile(actions) => self.apply_actions(&mut item, actions).await,
+        };
+        let name = self.destination.name();
+        let mut state = self.state.lock().await;
+        state.active.remove(&key);
+        match res {
+            Ok(()) => self.status.applied(&name, &key),
+            Err(e) => {
+                item.failures += 1;
+                self.failed(&mut state, key, item, e);
+            }
+        }
+        drop(state);
+        self.notify.notify();
+    }
+
+    /// Applies the actions of a reconciliation in order. When one fails, only
+    /// the actions left are kept in the item, to be retried
+    async fn apply_actions(
+        &self,
+        item: &mut Item,
+        actions: Vec<Action>,
+    ) -> Result<(), PublishError> {
+        let mut actions = actions.into_iter();
+        while let Some(action) = actions.next() {
+            info!("Reconciliation : {}", action);
+            let res = match &action {
+                Action::Create(envelope) | Action::Update(envelope) => {
+                    self.destination.publish(envelope.clone()).await
+                }
+                Action::Delete(published) => self
+                    .destination
+                    .remove(published)
+                    .await
+                    .map_err(PublishError::from),
+                Action::Attach(published, target) => self
+                    .destination
+                    .attach(published, target)
+                    .await
+                    .map_err(PublishError::from),
+                Action::Detach(published, target) => self
+                    .destination
+                    .detach(published, target)
+                    .await
+                    .map_err(PublishError::from),
+            };
+            if let Err(e) = res {
+                let left = std::iter::once(action).chain(actions).collect();
+                item.operation = Operation::Reconcile(left);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a failed change again, unless it was superseded, it cannot
+    /// succeed or it is out of attempts
+    fn failed(&self, state: &mut State, key: String, mut item: Box<Item>, e: PublishError) {
+        let name = self.destination.name();
+        if state.pending.contains_key(&key) {
+            warn!(
+                "Unable to {} to {}, superseded by a newer change : {}",
+                item.operation, name, e
+            );
+            return;
+        }
+        if !e.is_permanent() && item.failures < self.backoff.max_attempts() {
+            let delay = self.backoff.delay(item.failures - 1);
+            warn!(
+                "Unable to {} to {}, retry in {:?} : {}",
+                item.operation, name, delay, e
+            );
+            item.not_before = self.clock.now() + delay;
+            state.push(key, *item);
+            self.status.retried();
+            return;
+        }
+        error!(
+            "Unable to {} to {} after {} attempt(s), give up : {}",
+            item.operation, name, item.failures, e
+        );
+        let operation = item.operation.kind();
+        self.status
+            .failed(&name, &key, operation, &e.to_string(), item.failures);
+    }
+}
+
+#[async_trait]
+impl<'a> Destination for WorkQueue<'a> {
+    fn name(&self) -> String {
+        self.destination.name()
+    }
+
+    /// Queues the certificate, it is published later on
+    async fn publish(&self, envelope: Envelope) -> Result<(), PublishError> {
+        self.push(Operation::Publish(envelope)).await;
+        Ok(())
+    }
+
+    /// Queues the removal, it is performed later on
+    async fn unpublish(&self, envelope: Envelope) -> anyhow::Result<()> {
+        self.push(Operation::Unpublish(envelope)).await;
+        Ok(())
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<Published>> {
+        self.destination.list().await
+    }
+
+    async fn targets(&self, envelope: &Envelope) -> anyhow::Result<Vec<String>> {
+        self.destination.targets(envelope).await
+    }
+
+    async fn attach(&self, published: &Published, target: &str) -> anyhow::Result<()> {
+        self.destination.attach(published, target).await
+    }
+
+    async fn detach(&self, published: &Published, target: &str) -> anyhow::Result<()> {
+        self.destination.detach(published, target).await
+    }
+
+    async fn remove(&self, published: &Published) -> anyhow::Result<()> {
+        self.destination.remove(published).await
+    }
+
+    fn accepts(&self, envelope: &Envelope) -> bool {
+        self.destination.accepts(envelope)
+    }
+
+    fn partitions(&self) -> Vec<&dyn Destination> {
+        self.destination.partitions()
+    }
+}
+
+/// Get config from file
+fn parse_config(config_str: &str) -> anyhow::Result<QueueRootConfig> {
+    let config: QueueRootConfig = serde_yaml::from_str(config_str)?;
+    debug!("Queue config : {:?}", config);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_config, Next, WorkQueue};
+    use crate::common::{Envelope, Metadata, ObjectRef, TLS};
+    use crate::destination::{Destination, PublishError, Published};
+    use crate::reconciler::Action;
+    use crate::status::Status;
+    use crate::throttle::{Clock, FakeClock};
+    use anyhow::anyhow;
+    use async_trait::async_trait;
+    use indoc::indoc;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Records the published versions, failing the first `failures` calls
+    /// and the first call of each of `failing`, or rejecting certificates
+    /// when `rejected` is set
+    #[derive(Default)]
+    struct Recorder {
+        failures: Mutex<u32>,
+        failing: Mutex<Vec<String>>,
+        rejected: Mutex<bool>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Recorder {
+        fn record(&self, call: String) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push(call.clone());
+            let mut failing = self.failing.lock().unwrap();
+            if let Some(index) = failing.iter().position(|failing| *failing == call) {
+                failing.remove(index);
+                return Err(anyhow!("Throttling"));
+            }
+            let mut failures = self.failures.lock().unwrap();
+            match *failures {
+                0 => Ok(()),
+                _ => {
+                    *failures -= 1;
+                    Err(anyhow!("Throttling"))
+                }
+            }
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Destination for Recorder {
+        fn name(&self) -> String {
+            String::from("recorder")
+        }
+
+        async fn publish(&self, envelope: Envelope) -> Result<(), PublishError> {
+            self.record(format!("publish {}", envelope.tls.domains.join(",")))?;
+            match *self.rejected.lock().unwrap() {
+                true => Err(PublishError::Rejected {
+                    id: envelope.id(),
+                    reason: String::from("expired"),
+                }),
+                false => Ok(()),
+            }
+        }
+
+        async fn unpublish(&self, envelope: Envelope) -> anyhow::Result<()> {
+            self.record(format!("unpublish {}", envelope.tls.domains.join(",")))
+        }
+
+        async fn list(&self) -> anyhow::Result<Vec<Published>> {
+            Ok(vec![])
+        }
+
+        async fn targets(&self, _envelope: &Envelope) -> anyhow::Result<Vec<String>> {
+            Ok(vec![])
+        }
+
+        async fn attach(&self, published: &Published, target: &str) -> anyhow::Result<()> {
+            self.record(format!("attach {} to {}", published.id, target))
+        }
+
+        async fn detach(&self, published: &Published, target: &str) -> anyhow::Result<()> {
+            self.record(format!("detach {} from {}", published.id, target))
+        }
+
+        async fn remove(&self, published: &Published) -> anyhow::Result<()> {
+            self.record(format!("remove {}", published.id))
+        }
+    }
+
+    fn published(name: &str) -> Published {
+        Published {
+            id: format!("default/{}", name),
+            reference: format!("arn:{}", name),
+            fingerprint: None,
+            targets: vec![],
+            holder: None,
+        }
+    }
+
+    /// An envelope of the object `name`, whose domain tells the version
+    fn envelope(name: &str, version: &str) -> Envelope {
+        let object = ObjectRef {
+            namespace: Some(String::from("default")),
+            name: String::from(name),
+            uid: None,
+        };
+        let tls = TLS {
+            domains: vec![String::from(version)],
+            ..Default::default()
+        };
+        Envelope::new(tls, Metadata::new(String::from("test"), object))
+    }
+
+    fn queue<'a>(
+        destination: &'a Recorder,
+        status: &'a Status,
+        clock: Arc<FakeClock>,
+    ) -> WorkQueue<'a> {
+        let config = parse_config(indoc!(
+            "
+            queue:
+              retry:
+                max_attempts: 3
+                initial_delay: 1000
+            "
+        ))
+        .unwrap();
+        WorkQueue::with_clock(destination, status, config.queue, clock)
+    }
+
+    /// Processes the queue until it is empty, waiting for retries
+    async fn drain(queue: &WorkQueue<'_>, clock: &FakeClock) {
+        loop {
+            let next = queue.state.lock().await.pop(clock.now());
+            match next {
+                Next::Process(key, item) => queue.process(key, item).await,
+                Next::Wait(Some(delay)) => clock.sleep(delay).await,
+                Next::Wait(None) => return,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_latest_change() -> anyhow::Result<()> {
+        let recorder = Recorder::default();
+        let status = Status::default();
+        let clock = Arc::new(FakeClock::new());
+        let queue = queue(&recorder, &status, clock.clone());
+        queue.publish(envelope("a", "a1")).await?;
+        queue.publish(envelope("b", "b1")).await?;
+        queue.publish(envelope("a", "a2")).await?;
+        queue.unpublish(envelope("b", "b2")).await?;
+        queue.publish(envelope("a", "a3")).await?;
+        drain(&queue, &clock).await;
+        assert_eq!(recorder.calls(), vec!["publish a3", "unpublish b2"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn one_worker_per_object() -> anyhow::Result<()> {
+        let recorder = Recorder::default();
+        let status = Status::default();
+        let queue = queue(&recorder, &status, Arc::new(FakeClock::new()));
+        let now = queue.clock.now();
+        queue.publish(envelope("a", "a1")).await?;
+        let (key, item) = match queue.state.lock().await.pop(now) {
+            Next::Process(key, item) => (key, item),
+            Next::Wait(_) => panic!("a1 should be processed"),
+        };
+        // a2 waits until a1 is done
+        queue.publish(envelope("a", "a2")).await?;
+        assert!(matches!(
+            queue.state.lock().await.pop(now),
+            Next::Wait(None)
+        ));
+        queue.process(key, item).await;
+        assert!(matches!(
+            queue.state.lock().await.pop(now),
+            Next::Process(_, _)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_failed_change() -> anyhow::Result<()> {
+        let recorder = Recorder::default();
+        *recorder.failures.lock().unwrap() = 2;
+        let status = Status::default();
+        let clock = Arc::new(FakeClock::new());
+        let queue = queue(&recorder, &status, clock.clone());
+        queue.publish(envelope("a", "a1")).await?;
+        drain(&queue, &clock).await;
+        assert_eq!(recorder.calls(), vec!["publish a1"; 3]);
+        let total: Duration = clock.sleeps().iter().sum();
+        assert!(total >= Duration::from_millis(1500));
+
+        // Given up after `max_attempts`
+        *recorder.failures.lock().unwrap() = 5;
+        queue.publish(envelope("b", "b1")).await?;
+        drain(&queue, &clock).await;
+        assert_eq!(recorder.calls().len(), 6);
+        let report = status.report();
+        assert_eq!(report["failures"][0]["id"], "default/b");
+        assert_eq!(report["failures"][0]["attempts"], 3);
+
+        // A later success clears the failure
+        queue.publish(envelope("b", "b2")).await?;
+        drain(&queue, &clock).await;
+        assert_eq!(status.report()["failures"], serde_json::json!([]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn give_up_permanent_failure() -> anyhow::Result<()> {
+        let recorder = Recorder::default();
+        *recorder.rejected.lock().unwrap() = true;
+        let status = Status::default();
+        let clock = Arc::new(FakeClock::new());
+        let queue = queue(&recorder, &status, clock.clone());
+        queue.publish(envelope("a", "a1")).await?;
+        drain(&queue, &clock).await;
+        assert_eq!(recorder.calls(), vec!["publish a1"]);
+        assert!(clock.sleeps().is_empty());
+        let failure = &status.report()["failures"][0];
+        assert_eq!(
+            failure["error"],
+            "certificate default/a is rejected : expired"
+        );
+        assert_eq!(failure["attempts"], 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_reconcile_actions_left() -> anyhow::Result<()> {
+        let recorder = Recorder::default();
+        *recorder.failing.lock().unwrap() = vec![String::from("attach default/a to l2")];
+        let status = Status::default();
+        let clock = Arc::new(FakeClock::new());
+        let queue = queue(&recorder, &status, clock.clone());
+        queue
+            .reconcile(vec![
+                Action::Detach(published("a"), String::from("l1")),
+                Action::Attach(published("a"), String::from("l2")),
+            ])
+            .await;
+        queue.reconcile(vec![Action::Delete(published("b"))]).await;
+        drain(&queue, &clock).await;
+        assert_eq!(
+            recorder.calls(),
+            vec![
+                "detach default/a from l1",
+                "attach default/a to l2",
+                "remove default/b",
+                "attach default/a to l2",
+            ]
+        );
+        assert_eq!(status.report()["failures"], serde_json::json!([]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn report_failed_reconcile_actions() -> anyhow::Result<()> {
+        let recorder = Recorder::default();
+        *recorder.failures.lock().unwrap() = 5;
+        let status = Status::default();
+        let clock = Arc::new(FakeClock::new());
+        let queue = queue(&recorder, &status, clock.clone());
+        queue.reconcile(vec![Action::Delete(published("b"))]).await;
+        drain(&queue, &clock).await;
+        assert_eq!(recorder.calls(), vec!["remove default/b"; 3]);
+        let failure = &status.report()["failures"][0];
+        assert_eq!(failure["id"], "default/b");
+        assert_eq!(failure["operation"], "reconcile");
+        assert_eq!(failure["attempts"], 3);
+        assert!(status
+            .metrics()
+            .contains("cert_sync_changes_retried_total 2\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_source_change_over_reconcile() -> anyhow::Result<()> {
+        let recorder = Recorder::default();
+        let status = Status::default();
+        let clock = Arc::new(FakeClock::new());
+        let queue = queue(&recorder, &status, clock.clone());
+        queue.publish(envelope("a", "a2")).await?;
+        queue
+            .reconcile(vec![Action::Update(envelope("a", "a1"))])
+            .await;
+        queue
+            .reconcile(vec![Action::Update(envelope("b", "b1"))])
+            .await;
+        queue.unpublish(envelope("b", "b2")).await?;
+        drain(&queue, &clock).await;
+        assert_eq!(recorder.calls(), vec!["publish a2", "unpublish b2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_config_test() -> anyhow::Result<()> {
+        let config = parse_config(indoc!(
+            "
+            queue:
+              workers: 8
+            "
+        ))?;
+        let queue = config.queue.unwrap();
+        assert_eq!(queue.workers, Some(8));
+        assert_eq!(queue.retry, None);
+        assert_eq!(parse_config("aws: {}")?.queue, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+use super::common::Envelope;
+use super::destination::{Destination, Published};
+use super::queue::WorkQueue;
+use super::source::{Inventory, Source};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use tokio::time::{delay_for, Duration};
+
+/// Interval between two reconciliations, unless overridden with `interval`
+const DEFAULT_INTERVAL: u64 = 300;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct ReconcilerRootConfig {
+    reconciler: Option<ReconcilerConfig>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct ReconcilerConfig {
+    /// Seconds between two reconciliations
+    interval: Option<u64>,
+}
+
+/// A change to apply to a destination so that it matches its source
+#[derive(Debug, Clone)]
+pub enum Action {
+    Create(Envelope),
+    Update(Envelope),
+    Delete(Published),
+    Attach(Published, String),
+    Detach(Published, String),
+}
+
+impl Action {
+    /// Identifies the source object whose certificate the action applies to
+    pub fn id(&self) -> String {
+        match self {
+            Action::Create(envelope) | Action::Update(envelope) => envelope.id(),
+            Action::Delete(published)
+            | Action::Attach(published, _)
+            | Action::Detach(published, _) => published.id.clone(),
+        }
+    }
+
+    fn is_publish(&self) -> bool {
+        matches!(self, Action::Create(_) | Action::Update(_))
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Create(envelope) => write!(f, "create {}", envelope.id()),
+            Action::Update(envelope) => write!(f, "update {}", envelope.id()),
+            Action::Delete(published) => {
+                write!(f, "delete {} ({})", published.id, published.reference)
+            }
+            Action::Attach(published, target) => {
+                write!(f, "attach {} to {}", published.id, target)
+            }
+            Action::Detach(published, target) => {
+                write!(f, "detach {} from {}", published.id, target)
+            }
+        }
+    }
+}
+
+/// The list of actions computed by comparing a source and a destination
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub actions: Vec<Action>,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |predicate: fn(&Action) -> bool| {
+            self.actions
+                .iter()
+                .filter(|action| predicate(action))
+                .count()
+        };
+        write!(
+            f,
+            "{} to create, {} to update, {} to delete, {} to attach, {} to detach",
+            count(|action| matches!(action, Action::Create(_))),
+            count(|action| matches!(action, Action::Update(_))),
+            count(|action| matches!(action, Action::Delete(_))),
+            count(|action| matches!(action, Action::Attach(_, _))),
+            count(|action| matches!(action, Action::Detach(_, _))),
+        )
+    }
+}
+
+impl Plan {
+    /// Computes the actions bringing the `actual` state of the destination to
+    /// the `desired` state of the source, attached to the `targets` resolved
+    /// for each identifier
+    ///
+    /// Published certificates whose source object is unreadable are left
+    /// untouched.
+    pub fn compute(
+        desired: Inventory,
+        mut targets: HashMap<String, Vec<String>>,
+        actual: Vec<Published>,
+    ) -> Self {
+        let mut actions: Vec<Action> = vec![];
+        let mut actual: HashMap<String, Published> = actual
+            .into_iter()
+            .map(|published| (published.id.clone(), published))
+            .collect();
+        for id in desired.unreadable {
+            actual.remove(&id);
+        }
+        for envelope in desired.certificates {
+            let id = envelope.id();
+            let targets = targets.remove(&id).unwrap_or_default();
+            let published = match actual.remove(&id) {
+                Some(published) => published,
+                None => {
+                    actions.push(Action::Create(envelope));
+                    continue;
+                }
+            };
+            let current: BTreeSet<&String> = published.targets.iter().collect();
+            let wanted: BTreeSet<&String> = targets.iter().collect();
+            for target in current.difference(&wanted) {
+                actions.push(Action::Detach(published.clone(), (*target).clone()));
+            }
+            if published.fingerprint.as_deref() != envelope.tls.fingerprint() {
+                // Publishing attaches the certificate to all its targets
+                actions.push(Action::Update(envelope));
+                continue;
+            }
+            for target in wanted.difference(&current) {
+                actions.push(Action::Attach(published.clone(), (*target).clone()));
+            }
+        }
+        let mut orphans: Vec<Published> = actual.into_values().collect();
+        orphans.sort_by(|left, right| left.id.cmp(&right.id));
+        actions.extend(orphans.into_iter().map(Action::Delete));
+        Plan { actions }
+    }
+}
+
+/// Periodically brings a destination in line with the sources routed to it,
+/// repairing any change made outside of cert-sync
+///
+/// All the sources of a destination are reconciled together, as the
+/// certificates of one source are orphans for the others. The actions go
+/// through the work queue of the destination, so that they are retried and
+/// never applied at the same time as a change of the same object.
+pub struct Reconciler<'a> {
+    sources: Vec<&'a dyn Source>,
+    queue: &'a WorkQueue<'a>,
+    interval: Duration,
+}
+
+impl<'a> Reconciler<'a> {
+    pub fn new(
+        sources: Vec<&'a dyn Source>,
+        queue: &'a WorkQueue<'a>,
+        config_str: &str,
+    ) -> anyhow::Result<Self> {
+        let config = parse_config(config_str)?;
+        let interval = config
+            .reconciler
+            .and_then(|reconciler| reconciler.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+        Ok(Reconciler {
+            sources,
+            queue,
+            interval: Duration::from_secs(interval),
+        })
+    }
+
+    /// Reconciles forever. The first run waits for one interval, so that the
+    /// initial listing of the source watcher is not processed twice
+    pub async fn run(&self) -> anyhow::Result<()> {
+        loop {
+            delay_for(self.interval).await;
+            if let Err(e) = self.reconcile().await {
+                let names: Vec<String> = self.sources.iter().map(|source| source.name()).collect();
+                error!(
+                    "Unable to reconcile {} with {} : {}",
+                    names.join(", "),
+                    self.queue.name(),
+                    e
+                );
+            }
+        }
+    }
+
+    pub async fn reconcile(&self) -> anyhow::Result<()> {
+        let mut desired = Inventory::default();
+        for source in &self.sources {
+            let inventory = source.list().await?;
+            desired.certificates.extend(inventory.certificates);
+            desired.unreadable.extend(inventory.unreadable);
+        }
+        let mut actions: Vec<Action> = vec![];
+        let partitions = self.queue.partitions();
+        if partitions.is_empty() {
+            actions = self.plan(self.queue, &desired).await?.actions;
+        }
+        for partition in partitions {
+            match self.plan(partition, &desired).await {
+                Ok(plan) => actions.extend(plan.actions),
+                Err(e) => error!("Unable to reconcile {} : {}", partition.name(), e),
+            }
+        }
+        for batch in by_object(actions) {
+            self.queue.reconcile(batch).await;
+        }
+        Ok(())
+    }
+
+    /// Computes the plan bringing a destination, or one of its partitions, in
+    /// line with the certificates it accepts
+    async fn plan(
+        &self,
+        destination: &dyn Destination,
+        desired: &Inventory,
+    ) -> anyhow::Result<Plan> {
+        let desired = Inventory {
+            certificates: desired
+                .certificates
+                .iter()
+                .filter(|envelope| destination.accepts(envelope))
+                .cloned()
+                .collect(),
+            unreadable: desired.unreadable.clone(),
+        };
+        // Targets are resolved before listing the destination, which may only
+        // know about the targets it has resolved
+        let mut targets: HashMap<String, Vec<String>> = HashMap::new();
+        for envelope in &desired.certificates {
+            targets.insert(envelope.id(), destination.targets(envelope).await?);
+        }
+        let actual = destination.list().await?;
+        let plan = Plan::compute(desired, targets, actual);
+        info!("Reconciliation plan of {} : {}", destination.name(), plan);
+        Ok(plan)
+    }
+}
+
+/// Groups the actions by source object, keeping their order. The certificate
+/// of an object is published once, as publishing reaches every partition of
+/// the destination
+fn by_object(actions: Vec<Action>) -> Vec<Vec<Action>> {
+    let mut batches: Vec<Vec<Action>> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for action in actions {
+        let index = *positions.entry(action.id()).or_insert_with(|| {
+            batches.push(vec![]);
+            batches.len() - 1
+        });
+        let batch = &mut batches[index];
+        if !(action.is_publish() && batch.iter().any(Action::is_publish)) {
+            batch.push(action);
+        }
+    }
+    batches
+}
+
+/// Get config from file
+fn parse_config(config_str: &str) -> anyhow::Result<ReconcilerRootConfig> {
+    let config: ReconcilerRootConfig = serde_yaml::from_str(config_str)?;
+    debug!("Reconciler config : {:?}", config);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{by_object, parse_config, Action, Plan};
+    use crate::common::{Envelope, Metadata, ObjectRef, TLS};
+    use crate::destination::Published;
+    use crate::source::Inventory;
+    use indoc::indoc;
+    use std::collections::HashMap;
+
+    fn envelope(id: &str) -> Envelope {
+        let mut parts = id.splitn(2, '/');
+        let object = ObjectRef {
+            namespace: parts.next().map(String::from),
+            name: parts.next().map(String::from).unwrap_or_default(),
+            uid: None,
+        };
+        Envelope::new(TLS::default(), Metadata::new(String::from("test"), object))
+    }
+
+    fn published(id: &str, fingerprint: Option<&str>, targets: &[&str]) -> Published {
+        Published {
+            id: String::from(id),
+            reference: format!("arn:{}", id),
+            fingerprint: fingerprint.map(String::from),
+            targets: targets.iter().map(|target| String::from(*target)).collect(),
+            holder: None,
+        }
+    }
+
+    fn targets(ids: &[&str], targets: &[&str]) -> HashMap<String, Vec<String>> {
+        ids.iter()
+            .map(|id| {
+                (
+                    String::from(*id),
+                    targets.iter().map(|target| String::from(*target)).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn compute_plan() {
+        let desired = Inventory {
+            certificates: vec![
+                envelope("ns/new"),
+                envelope("ns/changed"),
+                envelope("ns/detached"),
+            ],
+            unreadable: vec![String::from("ns/broken")],
+        };
+        let actual = vec![
+            published("ns/changed", Some("old"), &["a", "b"]),
+            published("ns/detached", None, &["a", "c"]),
+            published("ns/broken", None, &[]),
+            published("ns/deleted", None, &["a"]),
+        ];
+        let targets = targets(&["ns/new", "ns/changed", "ns/detached"], &["a", "b"]);
+        let plan = Plan::compute(desired, targets, actual);
+        let actions: Vec<String> = plan.actions.iter().map(Action::to_string).collect();
+        assert_eq!(
+            actions,
+            vec![
+                "create ns/new",
+                "update ns/changed",
+                "detach ns/detached from c",
+                "attach ns/detached to b",
+                "delete ns/deleted (arn:ns/deleted)",
+            ]
+        );
+        assert_eq!(
+            plan.to_string(),
+            "1 to create, 1 to update, 1 to delete, 1 to attach, 1 to detach"
+        );
+    }
+
+    #[test]
+    fn compute_empty_plan() {
+        let desired = Inventory {
+            certificates: vec![envelope("ns/same")],
+            unreadable: vec![],
+        };
+        let actual = vec![published("ns/same", None, &[])];
+        let plan = Plan::compute(desired, HashMap::new(), actual);
+        assert!(plan.actions.is_empty());
+    }
+
+    #[test]
+    fn group_actions_by_object() {
+        let a = published("default/a", None, &["l1"]);
+        let actions = vec![
+            Action::Detach(a.clone(), String::from("l1")),
+            Action::Create(envelope("default/b")),
+            Action::Update(envelope("default/a")),
+            // From another partition
+            Action::Create(envelope("default/b")),
+            Action::Attach(a, String::from("l2")),
+        ];
+        let batches: Vec<Vec<String>> = by_object(actions)
+            .iter()
+            .map(|batch| batch.iter().map(Action::to_string).collect())
+            .collect();
+        assert_eq!(
+            batches,
+            vec![
+                vec![
+                    "detach default/a from l1",
+                    "update default/a",
+                    "attach default/a to l2"
+                ],
+                vec!["create default/b"],
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_config_test() -> anyhow::Result<()> {
+        let config = parse_config(indoc!(
+            "
+            reconciler:
+              interval: 60
+            "
+        ))?;
+        assert_eq!(config.reconciler.unwrap().interval, Some(60));
+        let config = parse_config("aws: {}")?;
+        assert_eq!(config.reconciler, None);
+        Ok(())
+    }
+}
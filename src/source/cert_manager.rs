@@ -0,0 +1,360 @@
+use super::kubernetes::{
+    get_metadata_from_object, object_id, parse_config, read_tls, watch, KubernetesConfig,
+    WatchHandler, WatchedResource,
+};
+use super::Destination;
+use super::{Envelope, Inventory, Source, TlsConfig, TLS};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::{
+    api::{Api, ListParams},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+
+/// A cert-manager `Certificate`, limited to the fields used to find and
+/// describe the issued certificate
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct Certificate {
+    metadata: ObjectMeta,
+    spec: CertificateSpec,
+    status: Option<CertificateStatus>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CertificateSpec {
+    secret_name: String,
+    #[serde(default)]
+    dns_names: Vec<String>,
+    issuer_ref: Option<IssuerRef>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct IssuerRef {
+    name: String,
+    kind: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CertificateStatus {
+    #[serde(default)]
+    conditions: Vec<CertificateCondition>,
+    not_after: Option<String>,
+    renewal_time: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CertificateCondition {
+    #[serde(rename = "type")]
+    type_: String,
+    status: String,
+    observed_generation: Option<i64>,
+    message: Option<String>,
+}
+
+impl k8s_openapi::Resource for Certificate {
+    const API_VERSION: &'static str = "cert-manager.io/v1";
+    const GROUP: &'static str = "cert-manager.io";
+    const KIND: &'static str = "Certificate";
+    const VERSION: &'static str = "v1";
+}
+
+impl k8s_openapi::ListableResource for Certificate {
+    const LIST_KIND: &'static str = "CertificateList";
+}
+
+impl k8s_openapi::Metadata for Certificate {
+    type Ty = ObjectMeta;
+
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn metadata_mut(&mut self) -> &mut ObjectMeta {
+        &mut self.metadata
+    }
+}
+
+impl Certificate {
+    /// Tells whether cert-manager has issued the certificate for the current
+    /// spec, i.e. the `Ready` condition is `True` and not outdated
+    fn is_ready(&self) -> bool {
+        match self.ready_condition() {
+            Some(condition) => {
+                condition.status == "True"
+                    && match (condition.observed_generation, self.metadata.generation) {
+                        (Some(observed), Some(generation)) => observed >= generation,
+                        _ => true,
+                    }
+            }
+            None => false,
+        }
+    }
+
+    fn ready_condition(&self) -> Option<&CertificateCondition> {
+        self.status
+            .as_ref()?
+            .conditions
+            .iter()
+            .find(|condition| condition.type_ == "Ready")
+    }
+
+    /// Identifies the certificate as `namespace/name`
+    fn id(&self) -> String {
+        object_id(&self.metadata)
+    }
+}
+
+/// Watches cert-manager `Certificate` objects and reads the Secret they
+/// issue once ready
+///
+/// The Certificate, rather than its Secret, is selected with the `kubernetes`
+/// configuration and describes the certificate to destinations.
+pub struct CertManagerSource {
+    client: Client,
+    apis: Vec<Api<Certificate>>,
+    list_params: ListParams,
+    config: KubernetesConfig,
+    tls_config: TlsConfig,
+}
+
+#[async_trait]
+impl Source for CertManagerSource {
+    fn name(&self) -> String {
+        String::from("cert-manager Certificate Source")
+    }
+
+    async fn receive(&self, destination: &dyn Destination) -> anyhow::Result<()> {
+        watch(self, &self.apis, &self.list_params, destination).await
+    }
+
+    async fn list(&self) -> anyhow::Result<Inventory> {
+        let mut inventory = Inventory::default();
+        let mut certificates: Vec<Certificate> = vec![];
+        for api in &self.apis {
+            certificates.extend(api.list(&self.list_params).await?);
+        }
+        for certificate in certificates {
+            if !self.config.selects(&certificate.metadata) {
+                continue;
+            }
+            let id = certificate.id();
+            // A Certificate being issued or renewed keeps its published
+            // counterpart until it is ready again
+            if !certificate.is_ready() {
+                inventory.unreadable.push(id);
+                continue;
+            }
+            match self.convert_to_envelope(certificate).await {
+                Ok(Some(envelope)) => inventory.certificates.push(envelope),
+                Ok(None) => (),
+                Err(e) => {
+                    error!("Unable to read TLS from certificate {} : {}", id, e);
+                    inventory.unreadable.push(id);
+                }
+            }
+        }
+        Ok(inventory)
+    }
+}
+
+#[async_trait]
+impl WatchHandler<Certificate> for CertManagerSource {
+    fn config(&self) -> &KubernetesConfig {
+        &self.config
+    }
+
+    /// Publishes the certificate once cert-manager has issued it
+    async fn handle_applied(
+        &self,
+        destination: &dyn Destination,
+        certificate: Certificate,
+    ) -> anyhow::Result<()> {
+        if !certificate.is_ready() {
+            let message = certificate
+                .ready_condition()
+                .and_then(|condition| condition.message.clone())
+                .unwrap_or_default();
+            info!(
+                "Wait for certificate {} to be ready : {}",
+                certificate.id(),
+                message
+            );
+            return Ok(());
+        }
+        if let Some(envelope) = self.convert_to_envelope(certificate).await? {
+            info!(
+                "Will try to synchronize cert with domains {}",
+                envelope.tls.domains.join(", ")
+            );
+            destination.publish(envelope).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes the certificate from destinations, which only need its
+    /// identity and domains. The Secret is not read as cert-manager may
+    /// delete it along with the Certificate.
+    async fn handle_deletion(
+        &self,
+        destination: &dyn Destination,
+        certificate: Certificate,
+    ) -> anyhow::Result<()> {
+        info!("Certificate {} has been deleted", certificate.id());
+        let tls = TLS {
+            domains: certificate.spec.dns_names.clone(),
+            ..Default::default()
+        };
+        let metadata = get_metadata_from_object(self.name(), &certificate.metadata);
+        info!(
+            "Will try to remove cert with domains {}",
+            tls.domains.join(", ")
+        );
+        destination.unpublish(Envelope::new(tls, metadata)).await?;
+        Ok(())
+    }
+}
+
+impl CertManagerSource {
+    pub async fn new(config_str: &str) -> anyhow::Result<Self> {
+        let config = parse_config(config_str)?.kubernetes.unwrap_or_default();
+        let client = Client::try_default().await?;
+        Ok(CertManagerSource {
+            apis: config.apis(&client),
+            list_params: config.list_params(),
+            client,
+            config,
+            tls_config: TlsConfig::parse(config_str)?,
+        })
+    }
+
+    /// Tells whether the configuration asks for cert-manager Certificates
+    /// instead of Secrets
+    pub fn is_configured(config_str: &str) -> anyhow::Result<bool> {
+        let resource = parse_config(config_str)?
+            .kubernetes
+            .and_then(|config| config.resource);
+        Ok(resource == Some(WatchedResource::Certificate))
+    }
+
+    async fn convert_to_envelope(
+        &self,
+        certificate: Certificate,
+    ) -> anyhow::Result<Option<Envelope>> {
+        let namespace = certificate
+            .metadata
+            .namespace
+            .as_deref()
+            .ok_or_else(|| anyhow!("Certificate {} has no namespace", certificate.id()))?;
+        info!(
+            "Pick certificate {} issued by {}, renewal at {}",
+            certificate.id(),
+            certificate
+                .spec
+                .issuer_ref
+                .as_ref()
+                .map(describe_issuer)
+                .unwrap_or_default(),
+            certificate
+                .status
+                .as_ref()
+                .and_then(|status| status.renewal_time.as_deref())
+                .unwrap_or("unknown")
+        );
+        let api: Api<Secret> = Api::namespaced(self.client.clone(), namespace);
+        let secret = api.get(&certificate.spec.secret_name).await?;
+        let metadata = get_metadata_from_object(self.name(), &certificate.metadata);
+        match read_tls(&self.client, &secret, &self.tls_config).await? {
+            Some(tls) => {
+                let envelope = Envelope::new(tls, metadata);
+                envelope.validate()?;
+                Ok(Some(envelope))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Formats the issuer reference as `kind/name`
+fn describe_issuer(issuer: &IssuerRef) -> String {
+    format!(
+        "{}/{}",
+        issuer.kind.as_deref().unwrap_or("Issuer"),
+        issuer.name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CertManagerSource, Certificate};
+    use indoc::indoc;
+
+    fn certificate(status: &str, observed_generation: i64) -> Certificate {
+        let json = format!(
+            r#"{{
+                "apiVersion": "cert-manager.io/v1",
+                "kind": "Certificate",
+                "metadata": {{"name": "web", "namespace": "default", "generation": 2}},
+                "spec": {{
+                    "secretName": "web-tls",
+                    "dnsNames": ["example.org", "www.example.org"],
+                    "issuerRef": {{"name": "letsencrypt", "kind": "ClusterIssuer"}}
+                }},
+                "status": {{
+                    "conditions": [{{
+                        "type": "Ready",
+                        "status": "{}",
+                        "observedGeneration": {}
+                    }}],
+                    "renewalTime": "2026-11-01T00:00:00Z"
+                }}
+            }}"#,
+            status, observed_generation
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn parse_certificate() {
+        let certificate = certificate("True", 2);
+        assert_eq!("default/web", certificate.id());
+        assert_eq!("web-tls", certificate.spec.secret_name);
+        assert_eq!(
+            vec!["example.org", "www.example.org"],
+            certificate.spec.dns_names
+        );
+        assert_eq!(
+            Some("2026-11-01T00:00:00Z"),
+            certificate.status.unwrap().renewal_time.as_deref()
+        );
+    }
+
+    #[test]
+    fn detect_ready_certificates() {
+        assert!(certificate("True", 2).is_ready());
+        assert!(!certificate("False", 2).is_ready());
+        // The spec changed since the certificate was issued
+        assert!(!certificate("True", 1).is_ready());
+        assert!(!Certificate::default().is_ready());
+    }
+
+    #[test]
+    fn configure_certificate_source() -> anyhow::Result<()> {
+        let config_str = indoc!(
+            "
+            kubernetes:
+              resource: certificate
+            "
+        );
+        assert!(CertManagerSource::is_configured(config_str)?);
+        assert!(!CertManagerSource::is_configured("kubernetes: {}")?);
+        assert!(!CertManagerSource::is_configured("aws: {}")?);
+        Ok(())
+    }
+}
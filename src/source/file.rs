@@ -0,0 +1,436 @@
+use super::Destination;
+use super::{pem, Envelope, Metadata, ObjectRef, TlsConfig, TLS};
+use super::{Inventory, Source, Synchronized};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use notify::{DebouncedEvent, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::UNIX_EPOCH;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::Duration;
+
+/// Placeholder of the certificate name in file patterns
+const NAME_PLACEHOLDER: &str = "{name}";
+
+/// Certificate file of the certbot `live` directories
+const DEFAULT_CERT_PATTERN: &str = "{name}/fullchain.pem";
+
+/// Private key file of the certbot `live` directories
+const DEFAULT_KEY_PATTERN: &str = "{name}/privkey.pem";
+
+/// Seconds without changes before reading the files, unless overridden with
+/// `debounce`
+const DEFAULT_DEBOUNCE: u64 = 2;
+
+/// Seconds between two scans when polling, unless overridden with
+/// `poll_interval`
+const DEFAULT_POLL_INTERVAL: u64 = 30;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct FilesRootConfig {
+    files: Option<FilesConfig>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct FilesConfig {
+    /// Directories holding the certificates
+    directories: Vec<PathBuf>,
+    /// Path of the certificates, relative to a directory, with `{name}`
+    cert_pattern: Option<String>,
+    /// Path of the private keys, relative to a directory, with `{name}`
+    key_pattern: Option<String>,
+    debounce: Option<u64>,
+    /// Poll the directories instead of relying on inotify
+    poll: Option<bool>,
+    poll_interval: Option<u64>,
+}
+
+impl FilesConfig {
+    fn cert_pattern(&self) -> &str {
+        self.cert_pattern.as_deref().unwrap_or(DEFAULT_CERT_PATTERN)
+    }
+
+    fn key_pattern(&self) -> &str {
+        self.key_pattern.as_deref().unwrap_or(DEFAULT_KEY_PATTERN)
+    }
+}
+
+/// A certificate file paired with its private key file
+///
+/// # Fields
+///
+/// * `directory` - The configured directory holding both files
+/// * `name` - The part of the paths matching `{name}`
+#[derive(Debug, PartialEq)]
+struct CertificateFiles {
+    directory: PathBuf,
+    name: String,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl CertificateFiles {
+    /// Identifies the certificate as `directory/name`
+    fn object(&self) -> ObjectRef {
+        ObjectRef {
+            namespace: Some(self.directory.to_string_lossy().into_owned()),
+            name: self.name.clone(),
+            uid: None,
+        }
+    }
+}
+
+/// Reads certificates and private keys from directories, e.g. the ones
+/// written by certbot, and watches them for changes
+pub struct FileSource {
+    config: FilesConfig,
+    tls_config: TlsConfig,
+}
+
+#[async_trait]
+impl Source for FileSource {
+    fn name(&self) -> String {
+        String::from("File Source")
+    }
+
+    async fn receive(&self, destination: &dyn Destination) -> anyhow::Result<()> {
+        let (tx, mut rx) = unbounded_channel();
+        let directories = self.config.directories.clone();
+        let debounce = Duration::from_secs(self.config.debounce.unwrap_or(DEFAULT_DEBOUNCE));
+        let poll = self.config.poll.unwrap_or(false);
+        let poll_interval =
+            Duration::from_secs(self.config.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL));
+        let watch = tokio::task::spawn_blocking(move || {
+            watch(&directories, debounce, poll, poll_interval, tx)
+        });
+        let mut synchronized = Synchronized::default();
+        self.synchronize(destination, &mut synchronized).await;
+        while rx.recv().await.is_some() {
+            // Changes received during the synchronization are all covered by
+            // the next scan
+            while rx.try_recv().is_ok() {}
+            self.synchronize(destination, &mut synchronized).await;
+        }
+        watch.await??;
+        Err(anyhow!("Stopped watching certificate directories"))
+    }
+
+    async fn list(&self) -> anyhow::Result<Inventory> {
+        let mut inventory = Inventory::default();
+        for files in self.scan() {
+            let id = files.object().id();
+            match self.convert_to_envelope(&files) {
+                Ok(envelope) => inventory.certificates.push(envelope),
+                Err(e) => {
+                    error!("Unable to read TLS from {} : {}", id, e);
+                    inventory.unreadable.push(id);
+                }
+            }
+        }
+        Ok(inventory)
+    }
+}
+
+impl FileSource {
+    pub fn new(config_str: &str) -> anyhow::Result<Self> {
+        let config = parse_config(config_str)?
+            .files
+            .ok_or_else(|| anyhow!("No files configuration found"))?;
+        if config.directories.is_empty() {
+            return Err(anyhow!("No directory to read certificates from"));
+        }
+        for pattern in &[config.cert_pattern(), config.key_pattern()] {
+            if pattern.matches(NAME_PLACEHOLDER).count() != 1 {
+                return Err(anyhow!(
+                    "File pattern {} must hold {} once",
+                    pattern,
+                    NAME_PLACEHOLDER
+                ));
+            }
+        }
+        Ok(FileSource {
+            config,
+            tls_config: TlsConfig::parse(config_str)?,
+        })
+    }
+
+    /// Publishes the certificates whose files changed since the last call,
+    /// and unpublishes the ones whose files were removed
+    ///
+    /// Unreadable files, e.g. a key not matching its certificate while
+    /// certbot is still writing, are left as is until the next change.
+    async fn synchronize(&self, destination: &dyn Destination, synchronized: &mut Synchronized) {
+        let mut present: HashSet<String> = HashSet::new();
+        for files in self.scan() {
+            let id = files.object().id();
+            present.insert(id.clone());
+            let envelope = match self.convert_to_envelope(&files) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    warn!("Unable to read TLS from {} : {}", id, e);
+                    continue;
+                }
+            };
+            synchronized.publish(destination, envelope).await;
+        }
+        for id in synchronized.ids() {
+            if !present.contains(&id) {
+                synchronized.unpublish(destination, &id).await;
+            }
+        }
+    }
+
+    /// Lists the certificate files of every directory having a private key
+    fn scan(&self) -> Vec<CertificateFiles> {
+        let cert_pattern = self.config.cert_pattern();
+        let depth = cert_pattern.split('/').count();
+        let mut found: Vec<CertificateFiles> = vec![];
+        for directory in &self.config.directories {
+            let mut paths: Vec<PathBuf> = vec![];
+            if let Err(e) = walk(directory, depth, &mut paths) {
+                warn!("Unable to read directory {} : {}", directory.display(), e);
+                continue;
+            }
+            paths.sort();
+            for cert in paths {
+                let name = match cert
+                    .strip_prefix(directory)
+                    .ok()
+                    .and_then(Path::to_str)
+                    .and_then(|relative| match_name(cert_pattern, relative))
+                {
+                    Some(name) => String::from(name),
+                    None => continue,
+                };
+                let key =
+                    directory.join(self.config.key_pattern().replace(NAME_PLACEHOLDER, &name));
+                if !key.is_file() {
+                    debug!("No private key {} for {}", key.display(), cert.display());
+                    continue;
+                }
+                found.push(CertificateFiles {
+                    directory: directory.clone(),
+                    name,
+                    cert,
+                    key,
+                });
+            }
+        }
+        found
+    }
+
+    fn convert_to_envelope(&self, files: &CertificateFiles) -> anyhow::Result<Envelope> {
+        let cert = fs::read_to_string(&files.cert)?;
+        let key = TLS::normalize_key(&fs::read(&files.key)?, None)?;
+        let tls = TLS::from_pem_chain(pem::certificates(&cert)?, key, vec![], &self.tls_config)?;
+        let modified = fs::metadata(&files.cert)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)?;
+        let metadata = Metadata {
+            resource_version: Some(modified.as_secs().to_string()),
+            ..Metadata::new(self.name(), files.object())
+        };
+        let envelope = Envelope::new(tls, metadata);
+        envelope.validate()?;
+        Ok(envelope)
+    }
+}
+
+/// Watches the directories and notifies `changes` once files stopped changing
+/// for `debounce`. Directories are polled every `poll_interval` instead when
+/// `poll` is set, or when inotify is not available.
+fn watch(
+    directories: &[PathBuf],
+    debounce: Duration,
+    poll: bool,
+    poll_interval: Duration,
+    changes: UnboundedSender<()>,
+) -> anyhow::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let inotify: Option<RecommendedWatcher> = match poll {
+        true => None,
+        false => match start_watcher(tx.clone(), debounce, directories) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!("Unable to watch directories, fall back to polling : {}", e);
+                None
+            }
+        },
+    };
+    let _polling: Option<PollWatcher> = match inotify {
+        Some(_) => None,
+        None => Some(start_watcher(tx, poll_interval, directories)?),
+    };
+    for event in rx {
+        match event {
+            // Sent before debouncing
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => continue,
+            DebouncedEvent::Error(e, path) => {
+                warn!("Error while watching {:?} : {}", path, e);
+                continue;
+            }
+            _ => {
+                if changes.send(()).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn start_watcher<W: Watcher>(
+    tx: mpsc::Sender<DebouncedEvent>,
+    delay: Duration,
+    directories: &[PathBuf],
+) -> notify::Result<W> {
+    let mut watcher = W::new(tx, delay)?;
+    for directory in directories {
+        watcher.watch(directory, RecursiveMode::Recursive)?;
+    }
+    Ok(watcher)
+}
+
+/// Collects the files of `directory` and its subdirectories, down to `depth`
+/// levels, following symbolic links
+fn walk(directory: &Path, depth: usize, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if depth == 0 {
+        return Ok(());
+    }
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, depth - 1, paths)?;
+        } else if path.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Extracts the `{name}` part of `relative` if it matches `pattern`
+fn match_name<'a>(pattern: &str, relative: &'a str) -> Option<&'a str> {
+    let (prefix, suffix) = pattern.split_once(NAME_PLACEHOLDER)?;
+    let name = relative.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    match name.is_empty() || name.contains('/') {
+        true => None,
+        false => Some(name),
+    }
+}
+
+/// Get config from file
+fn parse_config(config_str: &str) -> anyhow::Result<FilesRootConfig> {
+    let config: FilesRootConfig = serde_yaml::from_str(config_str)?;
+    debug!("Files config : {:?}", config);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{match_name, parse_config, FileSource, FilesConfig, TlsConfig};
+    use crate::common::testing::{certificate, generate_key, to_pem};
+    use crate::source::Source;
+    use indoc::indoc;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn parse_config_test() -> anyhow::Result<()> {
+        let config_str = indoc!(
+            "
+            files:
+              directories:
+                - /etc/letsencrypt/live
+              cert_pattern: '{name}.crt'
+              key_pattern: '{name}.key'
+              debounce: 5
+              poll: true
+              poll_interval: 60
+            "
+        );
+        let config = parse_config(config_str)?.files.unwrap();
+        assert_eq!(
+            config.directories,
+            vec![PathBuf::from("/etc/letsencrypt/live")]
+        );
+        assert_eq!(config.cert_pattern(), "{name}.crt");
+        assert_eq!(config.key_pattern(), "{name}.key");
+        assert_eq!(config.debounce, Some(5));
+        assert_eq!(config.poll, Some(true));
+        assert_eq!(config.poll_interval, Some(60));
+        assert_eq!(parse_config("aws: {}")?.files, None);
+        assert!(FileSource::new("files:\n  directories: []").is_err());
+        assert!(FileSource::new(indoc!(
+            "
+            files:
+              directories: [/tmp]
+              key_pattern: privkey.pem
+            "
+        ))
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn match_names() {
+        let pattern = "{name}/fullchain.pem";
+        assert_eq!(
+            Some("example.org"),
+            match_name(pattern, "example.org/fullchain.pem")
+        );
+        assert_eq!(None, match_name(pattern, "example.org/privkey.pem"));
+        assert_eq!(None, match_name(pattern, "a/b/fullchain.pem"));
+        assert_eq!(None, match_name(pattern, "/fullchain.pem"));
+        assert_eq!(Some("web"), match_name("tls-{name}.crt", "tls-web.crt"));
+    }
+
+    #[tokio::test]
+    async fn list_certificate_files() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let key = generate_key();
+        let live = directory.path().join("example.org");
+        fs::create_dir(&live)?;
+        fs::write(
+            live.join("fullchain.pem"),
+            to_pem(&certificate("example.org", &key, None)),
+        )?;
+        fs::write(live.join("privkey.pem"), key.private_key_to_pem_pkcs8()?)?;
+        // Certificates without key are ignored
+        fs::create_dir(directory.path().join("missing.org"))?;
+        fs::write(directory.path().join("missing.org/fullchain.pem"), "")?;
+        fs::write(directory.path().join("README"), "")?;
+        // Unreadable certificates are reported
+        let broken = directory.path().join("broken.org");
+        fs::create_dir(&broken)?;
+        fs::write(broken.join("fullchain.pem"), "")?;
+        fs::write(broken.join("privkey.pem"), key.private_key_to_pem_pkcs8()?)?;
+
+        let source = FileSource {
+            config: FilesConfig {
+                directories: vec![directory.path().to_path_buf()],
+                ..Default::default()
+            },
+            // The test certificate only has a Common Name
+            tls_config: TlsConfig::parse("tls: {common_name_fallback: true}")?,
+        };
+        let inventory = source.list().await?;
+        assert_eq!(1, inventory.certificates.len());
+        let envelope = &inventory.certificates[0];
+        assert_eq!(
+            format!("{}/example.org", directory.path().display()),
+            envelope.id()
+        );
+        assert_eq!(vec!["example.org"], envelope.tls.domains);
+        assert_eq!(
+            vec![format!("{}/broken.org", directory.path().display())],
+            inventory.unreadable
+        );
+        Ok(())
+    }
+}
@@ -1,26 +1,193 @@
 use super::Destination;
-use super::TLS;
+use super::{pem, Envelope, Metadata, ObjectRef, TlsConfig, TLS};
+use super::{Inventory, Source};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
-use futures::TryStreamExt;
+use chrono::Utc;
+use futures::{stream, StreamExt, TryStreamExt};
 use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use k8s_openapi::ByteString;
 use kube::{
-    api::{Api, ListParams},
+    api::{Api, ListParams, Meta},
     Client,
 };
-use kube_runtime::utils::try_flatten_applied;
-use kube_runtime::watcher;
+use kube_runtime::watcher::{watcher, Event};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::convert::TryFrom;
 use std::str;
 
-use tokio::time::{delay_for, Duration};
+/// Certificates expiring within this number of days are reported
+const EXPIRY_WARNING_DAYS: i64 = 14;
+
+/// Annotation naming the Secret, in the same namespace, holding the
+/// passphrase of an encrypted private key
+const PASSPHRASE_ANNOTATION: &str = "cert-sync.io/key-passphrase-secret";
+
+/// Key of the passphrase in the Secret named by `PASSPHRASE_ANNOTATION`
+const PASSPHRASE_KEY: &str = "passphrase";
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(super) struct KubernetesRootConfig {
+    pub(super) kubernetes: Option<KubernetesConfig>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(super) struct KubernetesConfig {
+    pub(super) resource: Option<WatchedResource>,
+    namespaces: Option<NamespacesConfig>,
+    label_selector: Option<String>,
+    annotation: Option<String>,
+}
+
+/// The kind of objects holding the certificates
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum WatchedResource {
+    /// `kubernetes.io/tls` Secrets
+    Secret,
+    /// cert-manager `Certificate` objects, read from the Secret they issue
+    Certificate,
+}
+
+/// Namespaces to watch. Only the `allow` ones are watched when set, the
+/// `deny` ones are always ignored
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct NamespacesConfig {
+    allow: Option<Vec<String>>,
+    deny: Option<Vec<String>>,
+}
+
+impl KubernetesConfig {
+    /// Checks the object against the namespace deny list and the opt-in
+    /// annotation, which must be set to `"true"` when configured
+    pub(super) fn selects(&self, metadata: &ObjectMeta) -> bool {
+        let namespace = metadata.namespace.clone().unwrap_or_default();
+        let denied = matches!(
+            self.namespaces.as_ref().and_then(|namespaces| namespaces.deny.as_ref()),
+            Some(deny) if deny.contains(&namespace)
+        );
+        let opted_in = match self.annotation {
+            Some(ref annotation) => {
+                metadata
+                    .annotations
+                    .as_ref()
+                    .and_then(|annotations| annotations.get(annotation))
+                    .map(String::as_str)
+                    == Some("true")
+            }
+            None => true,
+        };
+        !denied && opted_in
+    }
+
+    /// One API per allowed namespace, or a single cluster wide one
+    pub(super) fn apis<K: k8s_openapi::Resource>(&self, client: &Client) -> Vec<Api<K>> {
+        let allowed_namespaces = self
+            .namespaces
+            .as_ref()
+            .and_then(|namespaces| namespaces.allow.as_ref());
+        match allowed_namespaces {
+            Some(namespaces) => namespaces
+                .iter()
+                .map(|namespace| Api::namespaced(client.clone(), namespace))
+                .collect(),
+            None => vec![Api::all(client.clone())],
+        }
+    }
+
+    pub(super) fn list_params(&self) -> ListParams {
+        match self.label_selector {
+            Some(ref label_selector) => ListParams::default().labels(label_selector),
+            None => ListParams::default(),
+        }
+    }
+}
+
+/// The handling of the objects of a watched resource, whose events are
+/// dispatched by `watch`
+#[async_trait]
+pub(super) trait WatchHandler<K: Send + 'static>: Sync {
+    fn config(&self) -> &KubernetesConfig;
+
+    /// Publishes a selected object which was created, updated or listed
+    async fn handle_applied(&self, destination: &dyn Destination, object: K) -> anyhow::Result<()>;
+
+    /// Unpublishes a selected object which was deleted
+    async fn handle_deletion(&self, destination: &dyn Destination, object: K)
+        -> anyhow::Result<()>;
+}
+
+/// Watches the objects of every API, passing the selected ones to `handler`
+/// until the watch fails
+pub(super) async fn watch<K, H>(
+    handler: &H,
+    apis: &[Api<K>],
+    list_params: &ListParams,
+    destination: &dyn Destination,
+) -> anyhow::Result<()>
+where
+    K: Meta + Clone + DeserializeOwned + Send + 'static,
+    H: WatchHandler<K>,
+{
+    let applied = |object: K| async move {
+        if !handler.config().selects(object.meta()) {
+            debug!(
+                "Ignore {} {} as it is not selected",
+                K::KIND,
+                object_id(object.meta())
+            );
+            return;
+        }
+        if let Err(e) = handler.handle_applied(destination, object).await {
+            error!("Error while receiving TLS : {}", e);
+        }
+    };
+    loop {
+        let watchers = apis
+            .iter()
+            .map(|api| watcher(api.clone(), list_params.clone()).boxed());
+        stream::select_all(watchers)
+            .try_for_each(|event| async move {
+                match event {
+                    Event::Applied(object) => applied(object).await,
+                    Event::Deleted(object) => {
+                        if !handler.config().selects(object.meta()) {
+                            return Ok(());
+                        }
+                        if let Err(e) = handler.handle_deletion(destination, object).await {
+                            error!("Error while removing TLS : {}", e);
+                        }
+                    }
+                    Event::Restarted(objects) => {
+                        for object in objects {
+                            applied(object).await;
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .await?;
+    }
+}
+
+/// Identifies the object as `namespace/name`
+pub(super) fn object_id(metadata: &ObjectMeta) -> String {
+    format!(
+        "{}/{}",
+        metadata.namespace.as_deref().unwrap_or_default(),
+        metadata.name.as_deref().unwrap_or_default()
+    )
+}
 
 pub struct SecretSource {
-    api: Api<Secret>,
+    client: Client,
+    apis: Vec<Api<Secret>>,
     list_params: ListParams,
+    config: KubernetesConfig,
+    tls_config: TlsConfig,
 }
 
 #[async_trait]
@@ -29,69 +196,107 @@ impl super::Source for SecretSource {
         String::from("Kubernetes Secret Source")
     }
 
-    async fn receive<'a, T: Destination + Send + Sync>(
-        &'a self,
-        destination: &'a T,
-    ) -> anyhow::Result<()> {
-        loop {
-            let watcher = watcher(self.api.clone(), self.list_params.clone());
-            try_flatten_applied(watcher)
-                .try_for_each(|secret| async move {
-                    if let Err(e) = self.handle_certificate(destination, secret).await {
-                        error!("Error while receiving TLS : {}", e);
-                    }
-                    // Delay to avoid throttling on destination side
-                    delay_for(Duration::from_secs(1)).await;
-                    Ok(())
-                })
-                .await?;
+    async fn receive(&self, destination: &dyn Destination) -> anyhow::Result<()> {
+        watch(self, &self.apis, &self.list_params, destination).await
+    }
+
+    async fn list(&self) -> anyhow::Result<Inventory> {
+        let mut inventory = Inventory::default();
+        let mut secrets: Vec<Secret> = vec![];
+        for api in &self.apis {
+            secrets.extend(api.list(&self.list_params).await?);
+        }
+        for secret in secrets {
+            if !self.config.selects(&secret.metadata) {
+                continue;
+            }
+            let id = SecretSource::get_id_from_secret(&secret);
+            match self.convert_to_envelope(secret).await {
+                Ok(Some(envelope)) => inventory.certificates.push(envelope),
+                Ok(None) => (),
+                Err(e) => {
+                    error!("Unable to read TLS from secret {} : {}", id, e);
+                    inventory.unreadable.push(id);
+                }
+            }
         }
+        Ok(inventory)
     }
 }
 
-impl SecretSource {
-    pub async fn new(_config: &str) -> anyhow::Result<Self> {
-        let client = Client::try_default().await?;
-        let api: Api<Secret> = Api::all(client);
-        let list_params = ListParams::default().fields("type=kubernetes.io/tls");
-        Ok(SecretSource { api, list_params })
+#[async_trait]
+impl WatchHandler<Secret> for SecretSource {
+    fn config(&self) -> &KubernetesConfig {
+        &self.config
     }
 
-    async fn handle_certificate<'a, T: Destination + Send + Sync>(
-        &'a self,
-        destination: &'a T,
+    async fn handle_applied(
+        &self,
+        destination: &dyn Destination,
         secret: Secret,
     ) -> anyhow::Result<()> {
-        if let Some(tls) = self.convert_to_tls(secret)? {
+        if let Some(envelope) = self.convert_to_envelope(secret).await? {
             info!(
                 "Will try to synchronize cert with domains {}",
-                tls.domains.join(", ")
+                envelope.tls.domains.join(", ")
             );
-            destination.publish(tls).await?;
+            destination.publish(envelope).await?;
         }
         Ok(())
     }
 
-    fn convert_to_tls(&self, secret: Secret) -> anyhow::Result<Option<TLS>> {
-        let secret_name = SecretSource::get_name_from_secret(&secret);
-        let secret_namespace = SecretSource::get_namespace_from_secret(&secret);
-        info!("Pick certificate {}:{}", secret_namespace, secret_name);
-        match secret.data {
-            Some(data) => {
-                let tls = TLS::try_from(data)?;
-                info!(
-                    "Received cert from secret {}:{}",
-                    secret_namespace, secret_name
-                );
-                Ok(Some(tls))
-            }
-            None => {
-                warn!(
-                    "No data found in secret {}:{}",
-                    secret_namespace, secret_name
-                );
-                Ok(None)
+    /// Removes the certificate from destinations, which only need its
+    /// identity and domains. Neither the key nor the chain is read, so that
+    /// a Secret holding a broken certificate can still be removed.
+    async fn handle_deletion(
+        &self,
+        destination: &dyn Destination,
+        secret: Secret,
+    ) -> anyhow::Result<()> {
+        info!(
+            "Secret {}:{} has been deleted",
+            SecretSource::get_namespace_from_secret(&secret),
+            SecretSource::get_name_from_secret(&secret)
+        );
+        let envelope = removal_envelope(self.name(), &secret, &self.tls_config);
+        info!(
+            "Will try to remove cert with domains {}",
+            envelope.tls.domains.join(", ")
+        );
+        destination.unpublish(envelope).await?;
+        Ok(())
+    }
+}
+
+impl SecretSource {
+    pub async fn new(config_str: &str) -> anyhow::Result<Self> {
+        let config = parse_config(config_str)?.kubernetes.unwrap_or_default();
+        let client = Client::try_default().await?;
+        let apis = config.apis(&client);
+        let list_params = config.list_params().fields("type=kubernetes.io/tls");
+        Ok(SecretSource {
+            client,
+            apis,
+            list_params,
+            config,
+            tls_config: TlsConfig::parse(config_str)?,
+        })
+    }
+
+    async fn convert_to_envelope(&self, secret: Secret) -> anyhow::Result<Option<Envelope>> {
+        info!(
+            "Pick certificate {}:{}",
+            SecretSource::get_namespace_from_secret(&secret),
+            SecretSource::get_name_from_secret(&secret)
+        );
+        let metadata = get_metadata_from_object(self.name(), &secret.metadata);
+        match read_tls(&self.client, &secret, &self.tls_config).await? {
+            Some(tls) => {
+                let envelope = Envelope::new(tls, metadata);
+                envelope.validate()?;
+                Ok(Some(envelope))
             }
+            None => Ok(None),
         }
     }
 
@@ -102,6 +307,15 @@ impl SecretSource {
         }
     }
 
+    /// Identifies the secret as `namespace/name`
+    fn get_id_from_secret(secret: &Secret) -> String {
+        format!(
+            "{}/{}",
+            SecretSource::get_namespace_from_secret(secret),
+            SecretSource::get_name_from_secret(secret)
+        )
+    }
+
     fn get_namespace_from_secret(secret: &Secret) -> String {
         match secret.metadata.namespace {
             Some(ref namespace) => namespace.clone(),
@@ -110,20 +324,269 @@ impl SecretSource {
     }
 }
 
-impl TryFrom<BTreeMap<String, ByteString>> for TLS {
-    type Error = anyhow::Error;
+/// Reads the TLS held by a Secret, decrypting its key with the passphrase
+/// referenced by the Secret, if any
+pub(super) async fn read_tls(
+    client: &Client,
+    secret: &Secret,
+    config: &TlsConfig,
+) -> anyhow::Result<Option<TLS>> {
+    let secret_name = SecretSource::get_name_from_secret(secret);
+    let secret_namespace = SecretSource::get_namespace_from_secret(secret);
+    let data = match secret.data {
+        Some(ref data) => data,
+        None => {
+            warn!(
+                "No data found in secret {}:{}",
+                secret_namespace, secret_name
+            );
+            return Ok(None);
+        }
+    };
+    let passphrase = retrieve_passphrase(client, &secret.metadata).await?;
+    let tls = tls_from_data(data, passphrase.as_deref(), config)?;
+    info!(
+        "Received cert from secret {}:{}",
+        secret_namespace, secret_name
+    );
+    if let Some(ref info) = tls.info {
+        debug!("Certificate details : {}", info);
+        let margin = chrono::Duration::days(EXPIRY_WARNING_DAYS);
+        if info.expires_within(Utc::now(), margin) {
+            warn!(
+                "Certificate from secret {}:{} expires on {}",
+                secret_namespace,
+                secret_name,
+                info.not_after.to_rfc3339()
+            );
+        }
+    }
+    Ok(Some(tls))
+}
+
+/// Reads the passphrase of the private key from the Secret referenced by the
+/// passphrase annotation, if any
+async fn retrieve_passphrase(
+    client: &Client,
+    metadata: &ObjectMeta,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let name = match metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(PASSPHRASE_ANNOTATION))
+    {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let namespace = metadata.namespace.as_deref().unwrap_or("default");
+    let api: Api<Secret> = Api::namespaced(client.clone(), namespace);
+    let secret = api.get(name).await?;
+    match secret.data.and_then(|mut data| data.remove(PASSPHRASE_KEY)) {
+        Some(passphrase) => Ok(Some(passphrase.0)),
+        None => Err(anyhow!(
+            "No {} found in secret {}:{}",
+            PASSPHRASE_KEY,
+            namespace,
+            name
+        )),
+    }
+}
+
+/// Describes the object for destinations, which may route on its annotations
+pub(super) fn get_metadata_from_object(source: String, object: &ObjectMeta) -> Metadata {
+    let reference = ObjectRef {
+        namespace: object.namespace.clone(),
+        name: object.name.clone().unwrap_or_default(),
+        uid: object.uid.clone(),
+    };
+    Metadata {
+        resource_version: object.resource_version.clone(),
+        labels: object.labels.clone().unwrap_or_default(),
+        annotations: object.annotations.clone().unwrap_or_default(),
+        ..Metadata::new(source, reference)
+    }
+}
 
-    fn try_from(value: BTreeMap<String, ByteString>) -> Result<Self, Self::Error> {
-        let cert = match value.get("tls.crt") {
-            Some(x) => String::from_utf8(x.0.clone())?,
-            None => return Err(anyhow!("Unable to get cert from secret")),
-        };
-        let key = match value.get("tls.key") {
-            Some(x) => String::from_utf8(x.0.clone())?,
-            None => return Err(anyhow!("Unable to get key from secret")),
+/// Get config from file
+pub(super) fn parse_config(config_str: &str) -> anyhow::Result<KubernetesRootConfig> {
+    let config: KubernetesRootConfig = serde_yaml::from_str(config_str)?;
+    debug!("Kubernetes config : {:?}", config);
+    Ok(config)
+}
+
+/// Describes a deleted Secret to destinations with its identity and the
+/// domains of its certificate, left empty when the certificate is unreadable
+fn removal_envelope(source: String, secret: &Secret, config: &TlsConfig) -> Envelope {
+    let domains = match secret.data.as_ref().and_then(|data| data.get("tls.crt")) {
+        Some(cert) => domains_from_pem(&cert.0, config).unwrap_or_else(|e| {
+            warn!(
+                "Unable to read domains from secret {} : {}",
+                SecretSource::get_id_from_secret(secret),
+                e
+            );
+            vec![]
+        }),
+        None => vec![],
+    };
+    let tls = TLS {
+        domains,
+        ..Default::default()
+    };
+    Envelope::new(tls, get_metadata_from_object(source, &secret.metadata))
+}
+
+/// Reads the domains of the first certificate of a certificate file
+fn domains_from_pem(cert: &[u8], config: &TlsConfig) -> anyhow::Result<Vec<String>> {
+    match pem::certificates(str::from_utf8(cert)?)?.into_iter().next() {
+        Some(cert) => Ok(TLS::from_pem(cert, String::new(), vec![], config)?.domains),
+        None => Ok(vec![]),
+    }
+}
+
+/// Builds the TLS out of the data of a Secret, decrypting its key with
+/// `passphrase` if needed
+fn tls_from_data(
+    value: &BTreeMap<String, ByteString>,
+    passphrase: Option<&[u8]>,
+    config: &TlsConfig,
+) -> anyhow::Result<TLS> {
+    let cert = match value.get("tls.crt") {
+        Some(x) => String::from_utf8(x.0.clone())?,
+        None => return Err(anyhow!("Unable to get cert from secret")),
+    };
+    let key = match value.get("tls.key") {
+        Some(x) => TLS::normalize_key(&x.0, passphrase)?,
+        None => return Err(anyhow!("Unable to get key from secret")),
+    };
+    let ca = match value.get("ca.crt") {
+        Some(x) => pem::certificates(str::from_utf8(&x.0)?)?,
+        None => vec![],
+    };
+    TLS::from_pem_chain(pem::certificates(&cert)?, key, ca, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_metadata_from_object, parse_config, removal_envelope, KubernetesConfig,
+        NamespacesConfig, TlsConfig, WatchedResource,
+    };
+    use crate::common::testing::{generate_key, to_pem, with_alt_names};
+    use indoc::indoc;
+    use k8s_openapi::api::core::v1::Secret;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+    use k8s_openapi::ByteString;
+    use std::collections::BTreeMap;
+
+    fn secret(namespace: &str, annotation: Option<&str>) -> Secret {
+        let annotations = annotation.map(|value| {
+            let mut annotations = BTreeMap::new();
+            annotations.insert(String::from("cert-sync.io/enabled"), String::from(value));
+            annotations
+        });
+        Secret {
+            metadata: ObjectMeta {
+                name: Some(String::from("tls")),
+                namespace: Some(String::from(namespace)),
+                annotations,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_config_full_test() -> anyhow::Result<()> {
+        let config_str = indoc!(
+            "
+            kubernetes:
+              namespaces:
+                allow:
+                  - default
+                deny:
+                  - kube-system
+              label_selector: app=web
+              annotation: cert-sync.io/enabled
+              resource: certificate
+            "
+        );
+        let config = parse_config(config_str)?.kubernetes.unwrap();
+        let namespaces = config.namespaces.unwrap();
+        assert_eq!(namespaces.allow, Some(vec![String::from("default")]));
+        assert_eq!(namespaces.deny, Some(vec![String::from("kube-system")]));
+        assert_eq!(config.label_selector, Some(String::from("app=web")));
+        assert_eq!(config.resource, Some(WatchedResource::Certificate));
+        assert_eq!(
+            config.annotation,
+            Some(String::from("cert-sync.io/enabled"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_config_minimal_test() -> anyhow::Result<()> {
+        let config = parse_config("aws: {}")?;
+        assert_eq!(config.kubernetes, None);
+        Ok(())
+    }
+
+    #[test]
+    fn select_secrets() {
+        let config = KubernetesConfig {
+            namespaces: Some(NamespacesConfig {
+                allow: None,
+                deny: Some(vec![String::from("kube-system")]),
+            }),
+            annotation: Some(String::from("cert-sync.io/enabled")),
+            ..Default::default()
         };
-        let mut certs = TLS::split_to_vec(cert)?;
-        let tls = TLS::from_pem(certs.drain(0..1).collect(), key, certs)?;
-        Ok(tls)
+        assert!(config.selects(&secret("default", Some("true")).metadata));
+        assert!(!config.selects(&secret("default", Some("false")).metadata));
+        assert!(!config.selects(&secret("default", None).metadata));
+        assert!(!config.selects(&secret("kube-system", Some("true")).metadata));
+        assert!(KubernetesConfig::default().selects(&secret("default", None).metadata));
+    }
+
+    #[test]
+    fn metadata_from_secret() {
+        let mut secret = secret("default", Some("true"));
+        secret.metadata.uid = Some(String::from("0b1c"));
+        secret.metadata.resource_version = Some(String::from("42"));
+        let metadata =
+            get_metadata_from_object(String::from("Kubernetes Secret Source"), &secret.metadata);
+        assert_eq!(metadata.source, "Kubernetes Secret Source");
+        assert_eq!(metadata.object.id(), "default/tls");
+        assert_eq!(metadata.object.uid, Some(String::from("0b1c")));
+        assert_eq!(metadata.resource_version, Some(String::from("42")));
+        assert_eq!(
+            metadata.annotations.get("cert-sync.io/enabled"),
+            Some(&String::from("true"))
+        );
+        assert!(metadata.labels.is_empty());
+    }
+
+    #[test]
+    fn remove_secret_with_broken_key() {
+        let cert = with_alt_names(None, &["example.org"], &[], &[], &generate_key());
+        let mut data = BTreeMap::new();
+        data.insert(
+            String::from("tls.crt"),
+            ByteString(to_pem(&cert).into_bytes()),
+        );
+        data.insert(String::from("tls.key"), ByteString(b"not a key".to_vec()));
+        let mut secret = secret("default", None);
+        secret.data = Some(data);
+        let envelope = removal_envelope(String::from("test"), &secret, &TlsConfig::default());
+        assert_eq!(envelope.id(), "default/tls");
+        assert_eq!(envelope.tls.domains, vec!["example.org"]);
+
+        // A key of another certificate does not matter either
+        let key = generate_key().private_key_to_pem_pkcs8().unwrap();
+        let data = secret.data.as_mut().unwrap();
+        data.insert(String::from("tls.key"), ByteString(key));
+        data.insert(String::from("tls.crt"), ByteString(b"not a cert".to_vec()));
+        let envelope = removal_envelope(String::from("test"), &secret, &TlsConfig::default());
+        assert_eq!(envelope.id(), "default/tls");
+        assert!(envelope.tls.domains.is_empty());
     }
 }
@@ -1,16 +1,94 @@
+mod cert_manager;
+mod file;
 mod kubernetes;
+mod vault;
 
-use super::common::TLS;
+use super::common::{pem, Envelope, Metadata, ObjectRef, TlsConfig, TLS};
 use super::destination::Destination;
 
 use async_trait::async_trait;
+pub use cert_manager::CertManagerSource;
+pub use file::FileSource;
 pub use kubernetes::SecretSource;
+use std::collections::HashMap;
+pub use vault::VaultSource;
+
+/// Represents the certificates currently held by a source
+///
+/// # Fields
+///
+/// * `certificates` - The certificates to synchronize
+/// * `unreadable` - Identifiers of objects that could not be converted, their
+///   counterpart in destinations must be left untouched
+#[derive(Debug, Default)]
+pub struct Inventory {
+    pub certificates: Vec<Envelope>,
+    pub unreadable: Vec<String>,
+}
+
+/// What a source published last, for sources polling their certificates
+/// rather than receiving events. Unchanged certificates are not published
+/// again, and removed ones can still be unpublished.
+#[derive(Default)]
+struct Synchronized {
+    certificates: HashMap<String, (Option<String>, Vec<String>, Metadata)>,
+}
+
+impl Synchronized {
+    /// Publishes the certificate unless its fingerprint did not change
+    async fn publish(&mut self, destination: &dyn Destination, envelope: Envelope) {
+        let id = envelope.id();
+        let fingerprint = envelope.tls.fingerprint().map(String::from);
+        if matches!(self.certificates.get(&id), Some((previous, _, _)) if *previous == fingerprint)
+        {
+            return;
+        }
+        let domains = envelope.tls.domains.clone();
+        let metadata = envelope.metadata.clone();
+        info!(
+            "Will try to synchronize cert with domains {}",
+            domains.join(", ")
+        );
+        match destination.publish(envelope).await {
+            Ok(()) => {
+                self.certificates
+                    .insert(id, (fingerprint, domains, metadata));
+            }
+            Err(e) => error!("Error while receiving TLS : {}", e),
+        }
+    }
+
+    /// Unpublishes the certificate, if it was published
+    async fn unpublish(&mut self, destination: &dyn Destination, id: &str) {
+        let (_, domains, metadata) = match self.certificates.remove(id) {
+            Some(certificate) => certificate,
+            None => return,
+        };
+        info!(
+            "{} has been removed, will try to remove cert with domains {}",
+            id,
+            domains.join(", ")
+        );
+        let tls = TLS {
+            domains,
+            ..Default::default()
+        };
+        if let Err(e) = destination.unpublish(Envelope::new(tls, metadata)).await {
+            error!("Error while removing TLS : {}", e);
+        }
+    }
+
+    fn ids(&self) -> Vec<String> {
+        self.certificates.keys().cloned().collect()
+    }
+}
 
 #[async_trait]
-pub trait Source {
+pub trait Source: Send + Sync {
     fn name(&self) -> String;
-    async fn receive<'a, T: Destination + Send + Sync>(
-        &'a self,
-        destination: &'a T,
-    ) -> anyhow::Result<()>;
+    /// Watches the source and forwards every certificate creation, update and
+    /// deletion to the destination
+    async fn receive(&self, destination: &dyn Destination) -> anyhow::Result<()>;
+    /// Lists every certificate currently held by the source
+    async fn list(&self) -> anyhow::Result<Inventory>;
 }
@@ -0,0 +1,812 @@
+use super::Destination;
+use super::{pem, Envelope, Metadata, ObjectRef, TlsConfig, TLS};
+use super::{Inventory, Source, Synchronized};
+use crate::common::Redacted;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Utc};
+use hyper::client::{Client, HttpConnector};
+use hyper::{Body, Method, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::Mutex as SyncMutex;
+use tokio::sync::Mutex;
+
+use tokio::time::{delay_for, Duration};
+
+/// Seconds between two reads of the KV paths and PKI expiry checks, unless
+/// overridden with `interval`
+const DEFAULT_INTERVAL: u64 = 300;
+
+/// Seconds before expiry at which PKI certificates are renewed, unless
+/// overridden with `renew_before`
+const DEFAULT_RENEW_BEFORE: u64 = 7 * 24 * 3600;
+
+/// Mount of the Kubernetes auth method, unless overridden with `mount`
+const DEFAULT_KUBERNETES_MOUNT: &str = "kubernetes";
+
+/// Service account token read for the Kubernetes auth method, unless
+/// overridden with `token_path`
+const DEFAULT_TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+/// Seconds before expiry at which the Vault token is renewed by logging in
+/// again
+const TOKEN_RENEW_MARGIN: i64 = 60;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct VaultRootConfig {
+    vault: Option<VaultConfig>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct VaultConfig {
+    /// Address of the Vault server, e.g. `https://vault.example.org:8200`
+    address: String,
+    auth: AuthConfig,
+    #[serde(default)]
+    kv: Vec<KvConfig>,
+    #[serde(default)]
+    pki: Vec<PkiConfig>,
+    /// Seconds between two reads of the KV paths and PKI expiry checks
+    interval: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AuthConfig {
+    Token(Redacted),
+    Kubernetes(KubernetesAuthConfig),
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig::Token(Redacted::default())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct KubernetesAuthConfig {
+    role: String,
+    mount: Option<String>,
+    token_path: Option<String>,
+}
+
+/// A KV v2 secret holding a certificate, its private key and optionally its
+/// chain, in the given fields
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct KvConfig {
+    mount: String,
+    path: String,
+    cert_field: Option<String>,
+    key_field: Option<String>,
+    chain_field: Option<String>,
+}
+
+/// A certificate issued by a PKI role
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct PkiConfig {
+    mount: String,
+    role: String,
+    common_name: String,
+    #[serde(default)]
+    alt_names: Vec<String>,
+    /// Requested lifetime, e.g. `720h`, defaults to the role one
+    ttl: Option<String>,
+    /// Seconds before expiry at which the certificate is renewed
+    renew_before: Option<u64>,
+    /// Annotations passed to destinations, e.g. for listener routing
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+    /// KV v2 secret the issued certificate is saved to, and read back from
+    /// after a restart instead of issuing a new one
+    store: Option<StoreConfig>,
+}
+
+/// A KV v2 secret, written by cert-sync
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct StoreConfig {
+    mount: String,
+    path: String,
+}
+
+impl StoreConfig {
+    fn data_path(&self) -> String {
+        format!("{}/data/{}", self.mount, self.path)
+    }
+}
+
+impl KvConfig {
+    fn object(&self) -> ObjectRef {
+        ObjectRef {
+            namespace: Some(self.mount.clone()),
+            name: self.path.clone(),
+            uid: None,
+        }
+    }
+}
+
+impl PkiConfig {
+    fn object(&self) -> ObjectRef {
+        ObjectRef {
+            namespace: Some(self.mount.clone()),
+            name: format!("{}/{}", self.role, self.common_name),
+            uid: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Response<T> {
+    data: T,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvSecret {
+    data: BTreeMap<String, Value>,
+    metadata: KvMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvMetadata {
+    version: u64,
+    custom_metadata: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredCertificate {
+    data: IssuedCertificate,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IssuedCertificate {
+    certificate: String,
+    private_key: String,
+    #[serde(default)]
+    ca_chain: Vec<String>,
+    serial_number: String,
+    /// Expiry, as a Unix timestamp
+    expiration: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Login {
+    auth: LoginAuth,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginAuth {
+    client_token: String,
+    lease_duration: i64,
+}
+
+/// A token obtained by logging in, valid until `expires_at`
+struct Token {
+    value: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Reads certificates from Vault KV v2 secrets, and issues certificates from
+/// Vault PKI roles, renewing them before they expire
+pub struct VaultSource {
+    client: Client<HttpsConnector<HttpConnector>>,
+    config: VaultConfig,
+    tls_config: TlsConfig,
+    token: SyncMutex<Option<Token>>,
+    /// Last certificate issued by each PKI role, by identifier. Locked while
+    /// issuing, so that a certificate is only issued once
+    issued: Mutex<HashMap<String, IssuedCertificate>>,
+}
+
+#[async_trait]
+impl Source for VaultSource {
+    fn name(&self) -> String {
+        String::from("Vault Source")
+    }
+
+    async fn receive(&self, destination: &dyn Destination) -> anyhow::Result<()> {
+        let interval = Duration::from_secs(self.config.interval.unwrap_or(DEFAULT_INTERVAL));
+        let mut synchronized = Synchronized::default();
+        loop {
+            for kv in &self.config.kv {
+                let id = kv.object().id();
+                match self.read_kv(kv).await {
+                    Ok(Some(envelope)) => synchronized.publish(destination, envelope).await,
+                    Ok(None) => synchronized.unpublish(destination, &id).await,
+                    Err(e) => error!("Unable to read TLS from {} : {}", id, e),
+                }
+            }
+            for pki in &self.config.pki {
+                match self.issue_pki(pki).await {
+                    Ok(envelope) => synchronized.publish(destination, envelope).await,
+                    Err(e) => error!("Unable to issue TLS for {} : {}", pki.object(), e),
+                }
+            }
+            delay_for(interval).await;
+        }
+    }
+
+    async fn list(&self) -> anyhow::Result<Inventory> {
+        let mut inventory = Inventory::default();
+        for kv in &self.config.kv {
+            match self.read_kv(kv).await {
+                Ok(Some(envelope)) => inventory.certificates.push(envelope),
+                Ok(None) => (),
+                Err(e) => {
+                    error!("Unable to read TLS from {} : {}", kv.object(), e);
+                    inventory.unreadable.push(kv.object().id());
+                }
+            }
+        }
+        for pki in &self.config.pki {
+            match self.issue_pki(pki).await {
+                Ok(envelope) => inventory.certificates.push(envelope),
+                Err(e) => {
+                    error!("Unable to issue TLS for {} : {}", pki.object(), e);
+                    inventory.unreadable.push(pki.object().id());
+                }
+            }
+        }
+        Ok(inventory)
+    }
+}
+
+impl VaultSource {
+    pub fn new(config_str: &str) -> anyhow::Result<Self> {
+        let config = parse_config(config_str)?
+            .vault
+            .ok_or_else(|| anyhow!("No vault configuration found"))?;
+        Ok(VaultSource {
+            client: Client::builder().build(HttpsConnector::new()),
+            config,
+            tls_config: TlsConfig::parse(config_str)?,
+            token: SyncMutex::new(None),
+            issued: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn tls_from_pem(&self, cert: &str, key: &str, chain: &[String]) -> anyhow::Result<TLS> {
+        let key = TLS::normalize_key(key.as_bytes(), None)?;
+        let mut ca: Vec<String> = vec![];
+        for bundle in chain {
+            ca.extend(pem::certificates(bundle)?);
+        }
+        TLS::from_pem_chain(pem::certificates(cert)?, key, ca, &self.tls_config)
+    }
+
+    /// Reads the certificate of a KV v2 secret, `None` if the secret does not
+    /// exist
+    async fn read_kv(&self, kv: &KvConfig) -> anyhow::Result<Option<Envelope>> {
+        let path = format!("{}/data/{}", kv.mount, kv.path);
+        let secret: KvSecret = match self.request(Method::GET, &path, None).await? {
+            Some(Response { data }) => data,
+            None => return Ok(None),
+        };
+        let field = |name: &Option<String>, default: &str| {
+            let name = name.as_deref().unwrap_or(default);
+            secret
+                .data
+                .get(name)
+                .ok_or_else(|| anyhow!("No {} field in Vault secret {}", name, kv.object()))
+        };
+        let cert = field(&kv.cert_field, "certificate")?
+            .as_str()
+            .ok_or_else(|| anyhow!("Certificate of {} is not a string", kv.object()))?;
+        let key = field(&kv.key_field, "private_key")?
+            .as_str()
+            .ok_or_else(|| anyhow!("Private key of {} is not a string", kv.object()))?;
+        let chain = match field(&kv.chain_field, "ca_chain") {
+            Ok(value) => chain_from_value(value)?,
+            Err(_) if kv.chain_field.is_none() => vec![],
+            Err(e) => return Err(e),
+        };
+        let metadata = Metadata {
+            resource_version: Some(secret.metadata.version.to_string()),
+            annotations: secret.metadata.custom_metadata.clone().unwrap_or_default(),
+            ..Metadata::new(self.name(), kv.object())
+        };
+        let envelope = Envelope::new(self.tls_from_pem(cert, key, &chain)?, metadata);
+        envelope.validate()?;
+        Ok(Some(envelope))
+    }
+
+    /// Returns the certificate last issued by the PKI role, after issuing a
+    /// new one if there is none yet or if it expires within `renew_before`
+    async fn issue_pki(&self, pki: &PkiConfig) -> anyhow::Result<Envelope> {
+        let id = pki.object().id();
+        let mut issued = self.issued.lock().await;
+        let renew_before = pki.renew_before.unwrap_or(DEFAULT_RENEW_BEFORE) as i64;
+        if let (None, Some(store)) = (issued.get(&id), &pki.store) {
+            match self.read_stored(store).await {
+                Ok(Some(certificate)) => {
+                    info!("Reuse certificate {} stored in Vault", id);
+                    issued.insert(id.clone(), certificate);
+                }
+                Ok(None) => (),
+                Err(e) => warn!("Unable to read stored certificate {} : {}", id, e),
+            }
+        }
+        let renewal = match issued.get(&id) {
+            Some(certificate) => certificate.expiration - renew_before,
+            None => i64::MIN,
+        };
+        if Utc::now().timestamp() >= renewal {
+            info!("Issue certificate {} from Vault", id);
+            let path = format!("{}/issue/{}", pki.mount, pki.role);
+            let mut body = json!({
+                "common_name": pki.common_name,
+                "alt_names": pki.alt_names.join(","),
+            });
+            if let Some(ref ttl) = pki.ttl {
+                body["ttl"] = json!(ttl);
+            }
+            let certificate: IssuedCertificate = self
+                .request(Method::POST, &path, Some(body))
+                .await?
+                .map(|response: Response<IssuedCertificate>| response.data)
+                .ok_or_else(|| anyhow!("Vault PKI role {} not found", path))?;
+            if let Some(expiration) = Utc.timestamp_opt(certificate.expiration, 0).single() {
+                info!(
+                    "Issued certificate {} expires on {}",
+                    id,
+                    expiration.to_rfc3339()
+                );
+            }
+            if let Some(ref store) = pki.store {
+                if let Err(e) = self.store(store, &certificate).await {
+                    warn!("Unable to store issued certificate {} : {}", id, e);
+                }
+            }
+            issued.insert(id.clone(), certificate);
+        }
+        let certificate = issued
+            .get(&id)
+            .ok_or_else(|| anyhow!("No certificate issued for {}", id))?;
+        let chain: Vec<String> = certificate.ca_chain.clone();
+        let metadata = Metadata {
+            resource_version: Some(certificate.serial_number.clone()),
+            annotations: pki.annotations.clone(),
+            ..Metadata::new(self.name(), pki.object())
+        };
+        let tls = self.tls_from_pem(&certificate.certificate, &certificate.private_key, &chain)?;
+        let envelope = Envelope::new(tls, metadata);
+        envelope.validate()?;
+        Ok(envelope)
+    }
+
+    /// Reads a certificate previously issued and stored in a KV v2 secret,
+    /// `None` if the secret does not exist
+    async fn read_stored(&self, store: &StoreConfig) -> anyhow::Result<Option<IssuedCertificate>> {
+        let stored: Option<Response<StoredCertificate>> =
+            self.request(Method::GET, &store.data_path(), None).await?;
+        Ok(stored.map(|response| response.data.data))
+    }
+
+    /// Saves an issued certificate to a KV v2 secret
+    async fn store(
+        &self,
+        store: &StoreConfig,
+        certificate: &IssuedCertificate,
+    ) -> anyhow::Result<()> {
+        let body = json!({ "data": certificate });
+        let _: Option<Value> = self
+            .request(Method::POST, &store.data_path(), Some(body))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends a request to the Vault API, `None` if the path does not exist
+    async fn request<R: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> anyhow::Result<Option<R>> {
+        let token = self.token().await?;
+        self.send(method, path, Some(&token), body).await
+    }
+
+    async fn send<R: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> anyhow::Result<Option<R>> {
+        let uri = format!("{}/v1/{}", self.config.address.trim_end_matches('/'), path);
+        let mut request = Request::builder().method(method).uri(&uri);
+        if let Some(token) = token {
+            request = request.header("X-Vault-Token", token);
+        }
+        let body = match body {
+            Some(body) => Body::from(serde_json::to_vec(&body)?),
+            None => Body::empty(),
+        };
+        let response = self.client.request(request.body(body)?).await?;
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await?;
+        match status {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(serde_json::from_slice(&bytes)?)),
+            status => Err(anyhow!(
+                "Vault answered {} to {} : {}",
+                status,
+                uri,
+                String::from_utf8_lossy(&bytes)
+            )),
+        }
+    }
+
+    /// Returns the configured token, or logs in with the Kubernetes auth
+    /// method when the last token expires soon
+    async fn token(&self) -> anyhow::Result<String> {
+        let kubernetes = match self.config.auth {
+            AuthConfig::Token(ref token) => return Ok(token.expose().clone()),
+            AuthConfig::Kubernetes(ref kubernetes) => kubernetes,
+        };
+        let margin = ChronoDuration::seconds(TOKEN_RENEW_MARGIN);
+        if let Some(ref token) = *self.token.lock().unwrap() {
+            if Utc::now() + margin < token.expires_at {
+                return Ok(token.value.clone());
+            }
+        }
+        let jwt = fs::read_to_string(
+            kubernetes
+                .token_path
+                .as_deref()
+                .unwrap_or(DEFAULT_TOKEN_PATH),
+        )?;
+        let path = format!(
+            "auth/{}/login",
+            kubernetes
+                .mount
+                .as_deref()
+                .unwrap_or(DEFAULT_KUBERNETES_MOUNT)
+        );
+        let body = json!({ "role": kubernetes.role, "jwt": jwt.trim() });
+        let login: Login = self
+            .send(Method::POST, &path, None, Some(body))
+            .await?
+            .ok_or_else(|| anyhow!("Vault auth method {} not found", path))?;
+        debug!("Logged in to Vault with role {}", kubernetes.role);
+        let token = login.auth.client_token.clone();
+        *self.token.lock().unwrap() = Some(Token {
+            value: login.auth.client_token,
+            expires_at: Utc::now() + ChronoDuration::seconds(login.auth.lease_duration),
+        });
+        Ok(token)
+    }
+}
+
+/// Reads a chain stored either as a PEM bundle or as a list of PEM
+/// certificates
+fn chain_from_value(value: &Value) -> anyhow::Result<Vec<String>> {
+    match value {
+        Value::String(bundle) => Ok(vec![bundle.clone()]),
+        Value::Array(certs) => certs
+            .iter()
+            .map(|cert| {
+                cert.as_str()
+                    .map(String::from)
+                    .ok_or_else(|| anyhow!("Chain certificates must be strings"))
+            })
+            .collect(),
+        _ => Err(anyhow!("Chain must be a string or a list of strings")),
+    }
+}
+
+/// Get config from file
+fn parse_config(config_str: &str) -> anyhow::Result<VaultRootConfig> {
+    let config: VaultRootConfig = serde_yaml::from_str(config_str)?;
+    debug!("Vault config : {:?}", config);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_config, AuthConfig, VaultSource};
+    use crate::common::testing::{certificate, generate_key, to_pem};
+    use crate::source::Source;
+    use chrono::Utc;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use indoc::indoc;
+    use serde_json::{json, Value};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    /// Answers `responses` by method and path, recording the requests
+    async fn mock_vault(
+        responses: Vec<(&'static str, &'static str, Value)>,
+    ) -> (SocketAddr, Arc<Mutex<Vec<(String, Value)>>>) {
+        let requests: Arc<Mutex<Vec<(String, Value)>>> = Arc::new(Mutex::new(vec![]));
+        let responses = Arc::new(responses);
+        let recorded = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let responses = responses.clone();
+            let recorded = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let responses = responses.clone();
+                    let recorded = recorded.clone();
+                    async move {
+                        let method = request.method().to_string();
+                        let path = request.uri().path().to_string();
+                        let token = request
+                            .headers()
+                            .get("X-Vault-Token")
+                            .map(|token| token.to_str().unwrap().to_string());
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                        recorded
+                            .lock()
+                            .unwrap()
+                            .push((format!("{} {} {:?}", method, path, token), body));
+                        let response = responses
+                            .iter()
+                            .find(|(m, p, _)| *m == method && *p == path)
+                            .map(|(_, _, value)| Response::new(Body::from(value.to_string())))
+                            .unwrap_or_else(|| {
+                                let mut response = Response::new(Body::empty());
+                                *response.status_mut() = hyper::StatusCode::NOT_FOUND;
+                                response
+                            });
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (address, requests)
+    }
+
+    fn issued_certificate(name: &str) -> (String, String) {
+        let key = generate_key();
+        let cert = to_pem(&certificate(name, &key, None));
+        let key = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert, key)
+    }
+
+    #[test]
+    fn parse_config_test() -> anyhow::Result<()> {
+        let config_str = indoc!(
+            "
+            vault:
+              address: https://vault.example.org:8200
+              auth:
+                kubernetes:
+                  role: cert-sync
+              kv:
+                - mount: secret
+                  path: tls/example-org
+                  cert_field: cert
+              pki:
+                - mount: pki
+                  role: web
+                  common_name: example.org
+                  alt_names:
+                    - www.example.org
+                  ttl: 720h
+              interval: 60
+            "
+        );
+        let config = parse_config(config_str)?.vault.unwrap();
+        assert_eq!(config.address, "https://vault.example.org:8200");
+        match config.auth {
+            AuthConfig::Kubernetes(kubernetes) => assert_eq!(kubernetes.role, "cert-sync"),
+            _ => panic!("Kubernetes auth expected"),
+        }
+        assert_eq!(config.kv[0].cert_field, Some(String::from("cert")));
+        assert_eq!(config.kv[0].object().id(), "secret/tls/example-org");
+        assert_eq!(config.pki[0].object().id(), "pki/web/example.org");
+        assert_eq!(config.pki[0].ttl, Some(String::from("720h")));
+        assert_eq!(config.interval, Some(60));
+        assert_eq!(parse_config("aws: {}")?.vault, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_kv_secrets() -> anyhow::Result<()> {
+        let (cert, key) = issued_certificate("example.org");
+        let secret = json!({
+            "data": {
+                "data": {"certificate": cert, "private_key": key},
+                "metadata": {
+                    "version": 3,
+                    "custom_metadata": {"cert-sync.io/alb-selector": "env=prod"}
+                }
+            }
+        });
+        let (address, requests) =
+            mock_vault(vec![("GET", "/v1/secret/data/tls/example-org", secret)]).await;
+        let config_str = indoc!(
+            "
+            vault:
+              address: http://ADDRESS
+              auth:
+                token: s.token
+              kv:
+                - mount: secret
+                  path: tls/example-org
+                - mount: secret
+                  path: tls/missing
+            tls:
+              common_name_fallback: true
+            "
+        );
+        let source = VaultSource::new(&config_str.replace("ADDRESS", &address.to_string()))?;
+        let inventory = source.list().await?;
+        assert_eq!(1, inventory.certificates.len());
+        assert!(inventory.unreadable.is_empty());
+        let envelope = &inventory.certificates[0];
+        assert_eq!("secret/tls/example-org", envelope.id());
+        assert_eq!(vec!["example.org"], envelope.tls.domains);
+        assert_eq!(Some(String::from("3")), envelope.metadata.resource_version);
+        assert_eq!(
+            Some(&String::from("env=prod")),
+            envelope
+                .metadata
+                .annotations
+                .get("cert-sync.io/alb-selector")
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            "GET /v1/secret/data/tls/example-org Some(\"s.token\")",
+            requests[0].0
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn issue_and_renew_pki_certificates() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let token_path = directory.path().join("token");
+        std::fs::write(&token_path, "jwt\n")?;
+        let (cert, key) = issued_certificate("example.org");
+        let login = json!({"auth": {"client_token": "s.login", "lease_duration": 3600}});
+        // Expires in an hour, far from the renewal margin of a minute
+        let issued = json!({
+            "data": {
+                "certificate": cert,
+                "private_key": key,
+                "ca_chain": [],
+                "serial_number": "01:02",
+                "expiration": Utc::now().timestamp() + 3600
+            }
+        });
+        let (address, requests) = mock_vault(vec![
+            ("POST", "/v1/auth/kubernetes/login", login),
+            ("POST", "/v1/pki/issue/web", issued),
+        ])
+        .await;
+        let config_str = indoc!(
+            "
+            vault:
+              address: http://ADDRESS
+              auth:
+                kubernetes:
+                  role: cert-sync
+                  token_path: TOKEN_PATH
+              pki:
+                - mount: pki
+                  role: web
+                  common_name: example.org
+                  alt_names: [www.example.org]
+                  renew_before: 60
+            tls:
+              common_name_fallback: true
+            "
+        );
+        let source = VaultSource::new(
+            &config_str
+                .replace("ADDRESS", &address.to_string())
+                .replace("TOKEN_PATH", &token_path.display().to_string()),
+        )?;
+        let inventory = source.list().await?;
+        assert_eq!("pki/web/example.org", inventory.certificates[0].id());
+        assert_eq!(
+            Some(String::from("01:02")),
+            inventory.certificates[0].metadata.resource_version
+        );
+        // Not renewed while far from expiry, and the token is reused
+        source.list().await?;
+        {
+            let requests = requests.lock().unwrap();
+            let paths: Vec<&str> = requests.iter().map(|(path, _)| path.as_str()).collect();
+            assert_eq!(
+                vec![
+                    "POST /v1/auth/kubernetes/login None",
+                    "POST /v1/pki/issue/web Some(\"s.login\")"
+                ],
+                paths
+            );
+            assert_eq!(json!({"role": "cert-sync", "jwt": "jwt"}), requests[0].1);
+            assert_eq!(
+                json!({"common_name": "example.org", "alt_names": "www.example.org"}),
+                requests[1].1
+            );
+        }
+        // Renewed once within `renew_before` of the expiry
+        source.issued.lock().await.values_mut().for_each(|issued| {
+            issued.expiration = Utc::now().timestamp() + 30;
+        });
+        source.list().await?;
+        assert_eq!(3, requests.lock().unwrap().len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reuse_stored_pki_certificates() -> anyhow::Result<()> {
+        let (cert, key) = issued_certificate("example.org");
+        let certificate = json!({
+            "certificate": cert,
+            "private_key": key,
+            "ca_chain": [],
+            "serial_number": "01:02",
+            "expiration": Utc::now().timestamp() + 3600
+        });
+        let stored = json!({
+            "data": {"data": certificate.clone(), "metadata": {"version": 1}}
+        });
+        let (address, requests) = mock_vault(vec![
+            ("GET", "/v1/secret/data/cert-sync/example-org", stored),
+            ("POST", "/v1/pki/issue/web", json!({ "data": certificate })),
+            (
+                "POST",
+                "/v1/secret/data/cert-sync/example-org",
+                json!({"data": {}}),
+            ),
+        ])
+        .await;
+        let config_str = indoc!(
+            "
+            vault:
+              address: http://ADDRESS
+              auth:
+                token: s.token
+              pki:
+                - mount: pki
+                  role: web
+                  common_name: example.org
+                  renew_before: 60
+                  store:
+                    mount: secret
+                    path: cert-sync/example-org
+            tls:
+              common_name_fallback: true
+            "
+        );
+        let source = VaultSource::new(&config_str.replace("ADDRESS", &address.to_string()))?;
+        // Read back after a restart, without issuing a new certificate
+        let inventory = source.list().await?;
+        assert_eq!(
+            Some(String::from("01:02")),
+            inventory.certificates[0].metadata.resource_version
+        );
+        assert_eq!(
+            "GET /v1/secret/data/cert-sync/example-org Some(\"s.token\")",
+            requests.lock().unwrap()[0].0
+        );
+        assert_eq!(1, requests.lock().unwrap().len());
+        // Stored again once renewed
+        source.issued.lock().await.values_mut().for_each(|issued| {
+            issued.expiration = Utc::now().timestamp() + 30;
+        });
+        source.list().await?;
+        let requests = requests.lock().unwrap();
+        assert_eq!(3, requests.len());
+        assert_eq!(
+            "POST /v1/secret/data/cert-sync/example-org Some(\"s.token\")",
+            requests[2].0
+        );
+        assert_eq!(json!("01:02"), requests[2].1["data"]["serial_number"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct StatusRootConfig {
+    status: Option<StatusConfig>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct StatusConfig {
+    /// Address of the HTTP server, e.g. `0.0.0.0:8080`. No server is started
+    /// when unset
+    listen: Option<SocketAddr>,
+}
+
+/// A change which failed for good, until a later change of the same object
+/// succeeds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Failure {
+    operation: String,
+    error: String,
+    attempts: u32,
+    at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct Counters {
+    applied: u64,
+    retried: u64,
+    failed: u64,
+    /// By destination, then source object
+    failures: BTreeMap<String, BTreeMap<String, Failure>>,
+}
+
+/// The outcome of the changes applied to destinations, served as JSON on
+/// `/status` and as Prometheus metrics on `/metrics`
+#[derive(Default)]
+pub struct Status {
+    counters: Mutex<Counters>,
+}
+
+impl Status {
+    /// Records a change applied to the destination, clearing the previous
+    /// failure of the object
+    pub fn applied(&self, destination: &str, id: &str) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.applied += 1;
+        if let Some(failures) = counters.failures.get_mut(destination) {
+            failures.remove(id);
+        }
+    }
+
+    /// Records a failed change which is going to be retried
+    pub fn retried(&self) {
+        self.counters.lock().unwrap().retried += 1;
+    }
+
+    /// Records a change which failed for good
+    pub fn failed(&self, destination: &str, id: &str, operation: &str, error: &str, attempts: u32) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.failed += 1;
+        let failure = Failure {
+            operation: String::from(operation),
+            error: String::from(error),
+            attempts,
+            at: Utc::now(),
+        };
+        counters
+            .failures
+            .entry(String::from(destination))
+            .or_default()
+            .insert(String::from(id), failure);
+    }
+
+    pub(crate) fn report(&self) -> serde_json::Value {
+        let counters = self.counters.lock().unwrap();
+        let failures: Vec<serde_json::Value> = counters
+            .failures
+            .iter()
+            .flat_map(|(destination, failures)| {
+                failures.iter().map(move |(id, failure)| {
+                    json!({
+                        "destination": destination,
+                        "id": id,
+                        "operation": failure.operation,
+                        "error": failure.error,
+                        "attempts": failure.attempts,
+                        "at": failure.at,
+                    })
+                })
+            })
+            .collect();
+        json!({ "failures": failures })
+    }
+
+    pub(crate) fn metrics(&self) -> String {
+        let counters = self.counters.lock().unwrap();
+        let mut metrics = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            writeln!(metrics, "# HELP {} {}", name, help).unwrap();
+            writeln!(metrics, "# TYPE {} counter", name).unwrap();
+            writeln!(metrics, "{} {}", name, value).unwrap();
+        };
+        counter(
+            "cert_sync_changes_applied_total",
+            "Changes applied to destinations",
+            counters.applied,
+        );
+        counter(
+            "cert_sync_changes_retried_total",
+            "Failed changes queued again",
+            counters.retried,
+        );
+        counter(
+            "cert_sync_changes_failed_total",
+            "Changes which failed for good",
+            counters.failed,
+        );
+        let name = "cert_sync_failed_certificates";
+        writeln!(
+            metrics,
+            "# HELP {} Certificates whose latest change failed for good",
+            name
+        )
+        .unwrap();
+        writeln!(metrics, "# TYPE {} gauge", name).unwrap();
+        for (destination, failures) in &counters.failures {
+            writeln!(
+                metrics,
+                "{}{{destination=\"{}\"}} {}",
+                name,
+                destination.replace('\\', "\\\\").replace('"', "\\\""),
+                failures.len()
+            )
+            .unwrap();
+        }
+        metrics
+    }
+
+    fn respond(&self, request: Request<Body>) -> Response<Body> {
+        let response = Response::builder();
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/") | (&Method::GET, "/status") => response
+                .header("Content-Type", "application/json")
+                .body(Body::from(self.report().to_string())),
+            (&Method::GET, "/metrics") => response
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(Body::from(self.metrics())),
+            _ => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+        }
+        .unwrap()
+    }
+
+    /// Serves the status over HTTP until an error occurs, if an address is
+    /// configured
+    pub async fn serve(self: Arc<Self>, config_str: &str) -> anyhow::Result<()> {
+        let listen = match parse_config(config_str)?.status.and_then(|s| s.listen) {
+            Some(listen) => listen,
+            None => return futures::future::pending().await,
+        };
+        info!("Serve status on {}", listen);
+        let make_service = make_service_fn(move |_| {
+            let status = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = status.respond(request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        Server::try_bind(&listen)
+            .map_err(|e| anyhow!("Unable to listen on {} : {}", listen, e))?
+            .serve(make_service)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Get config from file
+fn parse_config(config_str: &str) -> anyhow::Result<StatusRootConfig> {
+    let config: StatusRootConfig = serde_yaml::from_str(config_str)?;
+    debug!("Status config : {:?}", config);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_config, Status};
+    use hyper::{Body, Request, StatusCode};
+    use indoc::indoc;
+
+    #[test]
+    fn record_failures() {
+        let status = Status::default();
+        status.failed("aws", "default/a", "publish", "Throttling", 10);
+        status.failed("aws", "default/b", "unpublish", "Access denied", 1);
+        status.retried();
+        status.applied("aws", "default/b");
+
+        let report = status.report();
+        let failures = report["failures"].as_array().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0]["id"], "default/a");
+        assert_eq!(failures[0]["error"], "Throttling");
+        assert_eq!(failures[0]["attempts"], 10);
+
+        let metrics = status.metrics();
+        assert!(metrics.contains("cert_sync_changes_applied_total 1\n"));
+        assert!(metrics.contains("cert_sync_changes_retried_total 1\n"));
+        assert!(metrics.contains("cert_sync_changes_failed_total 2\n"));
+        assert!(metrics.contains("cert_sync_failed_certificates{destination=\"aws\"} 1\n"));
+    }
+
+    #[test]
+    fn respond_routes() {
+        let status = Status::default();
+        let get = |path: &str| {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            status.respond(request).status()
+        };
+        assert_eq!(get("/status"), StatusCode::OK);
+        assert_eq!(get("/metrics"), StatusCode::OK);
+        assert_eq!(get("/other"), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn parse_config_test() -> anyhow::Result<()> {
+        let config = parse_config(indoc!(
+            "
+            status:
+              listen: 0.0.0.0:8080
+            "
+        ))?;
+        let listen = config.status.unwrap().listen.unwrap();
+        assert_eq!(listen.port(), 8080);
+        assert_eq!(parse_config("aws: {}")?.status, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Requests per second allowed by a token bucket, unless overridden with
+/// `rate`
+const DEFAULT_RATE: f64 = 4.0;
+
+/// Requests allowed at once by a token bucket, unless overridden with `burst`
+const DEFAULT_BURST: u32 = 8;
+
+/// Attempts of a call before giving up, unless overridden with `max_attempts`
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Milliseconds before the first retry, unless overridden with
+/// `initial_delay`
+const DEFAULT_INITIAL_DELAY: u64 = 500;
+
+/// Milliseconds between two retries at most, unless overridden with
+/// `max_delay`
+const DEFAULT_MAX_DELAY: u64 = 20_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Requests per second
+    rate: Option<f64>,
+    /// Requests allowed at once after a quiet period
+    burst: Option<u32>,
+}
+
+impl RateLimitConfig {
+    /// Checks that the bucket fills up and holds at least one token
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match (self.rate, self.burst) {
+            (Some(rate), _) if rate.is_nan() || rate <= 0.0 => {
+                Err(anyhow!("Rate limit must be positive, got {}", rate))
+            }
+            (_, Some(0)) => Err(anyhow!("Rate limit burst must be at least 1")),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// Attempts of a call, including the first one
+    max_attempts: Option<u32>,
+    /// Milliseconds before the first retry, doubled on every retry
+    initial_delay: Option<u64>,
+    /// Milliseconds between two retries at most
+    max_delay: Option<u64>,
+}
+
+impl RetryConfig {
+    pub fn new(max_attempts: u32, initial_delay: u64, max_delay: u64) -> Self {
+        RetryConfig {
+            max_attempts: Some(max_attempts),
+            initial_delay: Some(initial_delay),
+            max_delay: Some(max_delay),
+        }
+    }
+
+    /// Fills the settings left unset with the ones of `defaults`
+    pub fn or(&self, defaults: RetryConfig) -> Self {
+        RetryConfig {
+            max_attempts: self.max_attempts.or(defaults.max_attempts),
+            initial_delay: self.initial_delay.or(defaults.initial_delay),
+            max_delay: self.max_delay.or(defaults.max_delay),
+        }
+    }
+}
+
+/// Tells the time and waits, so that throttling can be tested without
+/// actually waiting
+#[async_trait]
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    async fn sleep(&self, duration: Duration);
+}
+
+/// The clock of the Tokio runtime
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::delay_for(duration).await;
+    }
+}
+
+/// Spaces out calls to a rate-limited API
+///
+/// The bucket holds up to `burst` tokens and gains `rate` tokens per second,
+/// each call taking one. Callers wait in turn for a token when it is empty.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    clock: Arc<dyn Clock>,
+    /// Tokens available, as of the instant
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(config: Option<&RateLimitConfig>, clock: Arc<dyn Clock>) -> Self {
+        let rate = config
+            .and_then(|config| config.rate)
+            .unwrap_or(DEFAULT_RATE);
+        let burst = config
+            .and_then(|config| config.burst)
+            .unwrap_or(DEFAULT_BURST)
+            .max(1) as f64;
+        TokenBucket {
+            rate,
+            burst,
+            state: Mutex::new((burst, clock.now())),
+            clock,
+        }
+    }
+
+    /// Waits for a token and takes it
+    pub async fn acquire(&self) {
+        let mut state = self.state.lock().await;
+        self.refill(&mut state);
+        if state.0 < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - state.0) / self.rate);
+            debug!("Wait {:?} for the rate limit", wait);
+            self.clock.sleep(wait).await;
+            self.refill(&mut state);
+        }
+        state.0 = (state.0 - 1.0).max(0.0);
+    }
+
+    fn refill(&self, state: &mut (f64, Instant)) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_duration_since(state.1).as_secs_f64();
+        state.0 = (state.0 + elapsed * self.rate).min(self.burst);
+        state.1 = now;
+    }
+}
+
+/// Retries failed calls with an exponential backoff
+///
+/// The delay doubles on every retry up to `max_delay`, and half of it is
+/// random so that callers failing together do not retry together.
+pub struct Backoff {
+    max_attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+    clock: Arc<dyn Clock>,
+    /// Returns a random number between 0 and 1
+    jitter: fn() -> f64,
+}
+
+impl Backoff {
+    pub fn new(config: Option<&RetryConfig>, clock: Arc<dyn Clock>) -> Self {
+        let setting = |get: fn(&RetryConfig) -> Option<u64>, default: u64| {
+            Duration::from_millis(config.and_then(get).unwrap_or(default))
+        };
+        Backoff {
+            max_attempts: config
+                .and_then(|config| config.max_attempts)
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS)
+                .max(1),
+            initial_delay: setting(|config| config.initial_delay, DEFAULT_INITIAL_DELAY),
+            max_delay: setting(|config| config.max_delay, DEFAULT_MAX_DELAY),
+            clock,
+            jitter: rand::random::<f64>,
+        }
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Returns the delay before the given retry, the first one being 0
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exponential = self
+            .initial_delay
+            .checked_mul(2u32.saturating_pow(retry))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        exponential / 2 + exponential.mul_f64((self.jitter)() / 2.0)
+    }
+}
+
+/// Rate limits and retries the calls to an API
+pub struct Throttle {
+    bucket: TokenBucket,
+    backoff: Backoff,
+}
+
+impl Throttle {
+    pub fn new(
+        rate_limit: Option<&RateLimitConfig>,
+        retry: Option<&RetryConfig>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Throttle {
+            bucket: TokenBucket::new(rate_limit, clock.clone()),
+            backoff: Backoff::new(retry, clock),
+        }
+    }
+
+    /// Calls `operation` once a token is available, and again after a delay
+    /// as long as it fails with an error `retryable` accepts and attempts are
+    /// left
+    pub async fn call<T, E, F, Fut>(
+        &self,
+        mut operation: F,
+        retryable: impl Fn(&E) -> bool,
+    ) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: std::fmt::Display,
+    {
+        let mut retry = 0;
+        loop {
+            self.bucket.acquire().await;
+            match operation().await {
+                Err(e) if retry + 1 < self.backoff.max_attempts && retryable(&e) => {
+                    let delay = self.backoff.delay(retry);
+                    warn!("Retry in {:?} after error : {}", delay, e);
+                    self.backoff.clock.sleep(delay).await;
+                    retry += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// A clock whose time only passes when sleeping, recording the sleeps
+#[cfg(test)]
+pub(crate) struct FakeClock {
+    start: Instant,
+    sleeps: std::sync::Mutex<Vec<Duration>>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub(crate) fn new() -> Self {
+        FakeClock {
+            start: Instant::now(),
+            sleeps: std::sync::Mutex::new(vec![]),
+        }
+    }
+
+    pub(crate) fn sleeps(&self) -> Vec<Duration> {
+        self.sleeps.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + self.sleeps().iter().sum::<Duration>()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.sleeps.lock().unwrap().push(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, Clock, FakeClock, RateLimitConfig, RetryConfig, Throttle, TokenBucket};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn retry_config() -> RetryConfig {
+        RetryConfig {
+            max_attempts: Some(4),
+            initial_delay: Some(100),
+            max_delay: Some(300),
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limit_after_burst() {
+        let clock = Arc::new(FakeClock::new());
+        let config = RateLimitConfig {
+            rate: Some(2.0),
+            burst: Some(3),
+        };
+        let bucket = TokenBucket::new(Some(&config), clock.clone());
+        for _ in 0..5 {
+            bucket.acquire().await;
+        }
+        let half_second = Duration::from_millis(500);
+        assert_eq!(clock.sleeps(), vec![half_second, half_second]);
+        clock.sleep(Duration::from_secs(10)).await;
+        // The bucket holds up to `burst` tokens
+        for _ in 0..4 {
+            bucket.acquire().await;
+        }
+        assert_eq!(clock.sleeps().len(), 4);
+    }
+
+    #[test]
+    fn validate_rate_limit() {
+        let config = |rate: f64, burst: u32| RateLimitConfig {
+            rate: Some(rate),
+            burst: Some(burst),
+        };
+        assert!(config(0.5, 1).validate().is_ok());
+        assert!(config(0.0, 1).validate().is_err());
+        assert!(config(-1.0, 1).validate().is_err());
+        assert!(config(f64::NAN, 1).validate().is_err());
+        assert!(config(1.0, 0).validate().is_err());
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let mut backoff = Backoff::new(Some(&retry_config()), Arc::new(FakeClock::new()));
+        backoff.jitter = || 0.0;
+        assert_eq!(backoff.delay(0), Duration::from_millis(50));
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(150));
+        assert_eq!(backoff.delay(40), Duration::from_millis(150));
+        backoff.jitter = || 1.0;
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(5), Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn retry_retryable_errors() {
+        let clock = Arc::new(FakeClock::new());
+        let mut throttle = Throttle::new(None, Some(&retry_config()), clock.clone());
+        throttle.backoff.jitter = || 1.0;
+
+        let mut calls = 0;
+        let res: Result<u32, String> = throttle
+            .call(
+                || {
+                    calls += 1;
+                    let res = match calls {
+                        3 => Ok(calls),
+                        _ => Err(String::from("Throttling")),
+                    };
+                    async move { res }
+                },
+                |e| e == "Throttling",
+            )
+            .await;
+        assert_eq!(res, Ok(3));
+        let retries = vec![Duration::from_millis(100), Duration::from_millis(200)];
+        assert_eq!(clock.sleeps(), retries);
+
+        // Up to `max_attempts` calls
+        let mut calls = 0;
+        let res: Result<(), String> = throttle
+            .call(
+                || {
+                    calls += 1;
+                    async { Err(String::from("Throttling")) }
+                },
+                |e| e == "Throttling",
+            )
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls, 4);
+
+        // Other errors are returned at once
+        let mut calls = 0;
+        let res: Result<(), String> = throttle
+            .call(
+                || {
+                    calls += 1;
+                    async { Err(String::from("AccessDenied")) }
+                },
+                |e| e == "Throttling",
+            )
+            .await;
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+}
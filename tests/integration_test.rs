@@ -1,9 +1,12 @@
+// Helpers are kept for `create_certificate`, which is currently disabled
+#![allow(dead_code)]
+
 extern crate env_logger;
 extern crate rusoto_core;
 extern crate rusoto_elbv2;
 #[macro_use]
 extern crate log;
-use cert_sync::{Destination, Source, TLS};
+use cert_sync::{Destination, Envelope, Inventory, PublishError, Published, Source, TLS};
 
 use std::sync::{Arc, RwLock};
 
@@ -18,13 +21,14 @@ pub struct TestSource {}
 
 #[async_trait]
 impl Source for TestSource {
-    async fn receive<'a, T: Destination + Send + Sync>(
-        &'a self,
-        _destination: &'a T,
-    ) -> anyhow::Result<()> {
+    async fn receive(&self, _destination: &dyn Destination) -> anyhow::Result<()> {
         Ok(())
     }
 
+    async fn list(&self) -> anyhow::Result<Inventory> {
+        Ok(Inventory::default())
+    }
+
     fn name(&self) -> String {
         String::from("TestSource")
     }
@@ -36,9 +40,35 @@ pub struct TestDestination {
 
 #[async_trait]
 impl Destination for TestDestination {
-    async fn publish(&self, tls: TLS) -> anyhow::Result<()> {
+    async fn publish(&self, envelope: Envelope) -> Result<(), PublishError> {
         let mut tls_write = self.tls.write().unwrap();
-        *tls_write = tls;
+        *tls_write = envelope.tls;
+        Ok(())
+    }
+
+    async fn unpublish(&self, _envelope: Envelope) -> anyhow::Result<()> {
+        let mut tls_write = self.tls.write().unwrap();
+        *tls_write = TLS::default();
+        Ok(())
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<Published>> {
+        Ok(vec![])
+    }
+
+    async fn targets(&self, _envelope: &Envelope) -> anyhow::Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    async fn attach(&self, _published: &Published, _target: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn detach(&self, _published: &Published, _target: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn remove(&self, _published: &Published) -> anyhow::Result<()> {
         Ok(())
     }
 
@@ -97,11 +127,9 @@ async fn add_certificate(client: &Client, file: &str) {
         }
         Err(kube::Error::Api(ae)) => {
             dbg!(ae);
-            ()
         } // if you skipped delete, for instance
         Err(e) => {
             dbg!("something bad happened {}", e);
-            ()
         }
     }
 }
@@ -122,8 +150,8 @@ async fn create_certificate() {
         Vec::new(),
     )));
     // It currently tests with an existing Kubernetes cluster
-    let source = TestSource {};
-    let destination = TestDestination { tls: tls.clone() };
+    let _source = TestSource {};
+    let _destination = TestDestination { tls: tls.clone() };
 
     // Pre test cleanup
     let client = init_client().await;
@@ -136,11 +164,13 @@ async fn create_certificate() {
     // Pretty ugly but haven't find a better way of handling it
     std::thread::sleep(std::time::Duration::from_millis(500));
 
-    let tls_read = tls.read().unwrap();
     let kube_tls_secret: Secret =
         serde_yaml::from_str(kube_secret_file_path).expect("Unable to read file as YAML");
-    assert_eq!(get_data(&kube_tls_secret, "tls.crt"), tls_read.cert);
-    assert_eq!(get_data(&kube_tls_secret, "tls.key"), tls_read.key);
+    {
+        let tls_read = tls.read().unwrap();
+        assert_eq!(get_data(&kube_tls_secret, "tls.crt"), tls_read.cert);
+        assert_eq!(get_data(&kube_tls_secret, "tls.key"), tls_read.key);
+    }
 
     // Post test cleanup
     delete_certificates(&client).await;